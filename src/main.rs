@@ -1,20 +1,142 @@
 use clap::Parser;
+use std::path::PathBuf;
+use url::Url;
 
-use log::debug;
+use log::{debug, error, info, warn};
 
-use bundle_schema::util::{inputs, logging};
+use bundle_schema::util::{
+  bundler, cache, dereference, dialect, inputs, logging, migrate, output, prune, retriever,
+};
 
 #[derive(Parser, Debug)]
 #[command(version, about)]
 struct CliArgs {
   #[arg(short = 'o', long, value_name = "OUTPUT_FILE")]
-  /// The output file to use for the bundled output.
+  /// The output file to use for the bundled output. Omit it, or use `-`, to write to stdout.
   output: Option<String>,
 
+  #[arg(long, conflicts_with = "compact")]
+  /// Pretty-print the output (the default).
+  pretty: bool,
+
+  #[arg(long)]
+  /// Write the output on a single line.
+  compact: bool,
+
+  #[arg(
+    long,
+    value_name = "WIDTH",
+    default_value_t = 2,
+    conflicts_with = "compact"
+  )]
+  /// The number of spaces to indent each level by when pretty-printing.
+  indent: usize,
+
   #[arg(short = 'i', long, value_name = "INPUT FILES")]
   /// The input files to use in bundling. Use the flag multiple times for multiple files, like `-i foo.json -i bar.json`.
+  /// Directories are searched recursively for `.json`, `.jsonc`, `.json5`, `.yaml` and `.yml`
+  /// files, and glob patterns like `'schemas/**/*.schema.json'` are expanded. Use `-` to read a
+  /// schema from stdin.
   input: Vec<String>,
 
+  #[arg(long, value_enum, value_name = "FORMAT")]
+  /// The format of every input. Without it, each input's format is worked out from its file extension,
+  /// or by looking at its contents.
+  input_format: Option<inputs::InputFormat>,
+
+  #[arg(long, value_name = "URI")]
+  /// Give inputs without an `$id` one made from this base URI joined with their path relative to the
+  /// directory or glob they were found through. Without it, such inputs get a `file://` URL instead.
+  base_uri: Option<Url>,
+
+  #[arg(long, value_name = "PREFIX=DIR")]
+  /// Load referenced schemas whose URI starts with PREFIX from files beneath DIR when they aren't among
+  /// the inputs, like `--map https://schemas.example.com/=./schemas/`. Use the flag multiple times for
+  /// multiple mappings.
+  map: Vec<retriever::UriMapping>,
+
+  #[arg(long)]
+  /// Fetch referenced `http://` and `https://` schemas that aren't among the inputs, or mapped with
+  /// `--map`, over the network. Without it, such references are left as they are and reported.
+  fetch: bool,
+
+  #[arg(long, value_name = "DIR", default_value = cache::DEFAULT_CACHE_DIR)]
+  /// Where to cache schemas fetched over the network.
+  cache_dir: PathBuf,
+
+  #[arg(long, value_name = "FILE", default_value = cache::DEFAULT_LOCKFILE)]
+  /// Where to record the content hash of every schema fetched over the network. Fetched schemas
+  /// whose contents don't match their recorded hash are refused.
+  lockfile: PathBuf,
+
+  #[arg(long)]
+  /// Load remote schemas from the cache instead of fetching them over the network; every remote
+  /// schema must already be cached. Overrides `--fetch`.
+  offline: bool,
+
+  #[arg(long)]
+  /// Like `--offline`, and also refuse to add anything to the lockfile, so every remote schema
+  /// must already be cached and locked.
+  frozen: bool,
+
+  #[arg(long, value_name = "PATTERN")]
+  /// Skip input files matching this glob pattern. Patterns without a `/` match the file name alone.
+  exclude: Vec<String>,
+
+  #[arg(long, value_name = "DIR", conflicts_with = "output")]
+  /// Write one bundle per root into this directory, named after each root's relative `$id`.
+  out_dir: Option<PathBuf>,
+
+  #[arg(long, value_name = "FILE_OR_ID")]
+  /// The input file, or `$id`, of the schema to use as the top-level document. Other inputs are
+  /// only included when they're reachable from it. Defaults to the first input. Use the flag
+  /// multiple times, along with `--out-dir`, to produce one bundle per root.
+  root: Vec<String>,
+
+  #[arg(long, value_enum, value_name = "DIALECT", default_value_t)]
+  /// The JSON Schema dialect of inputs without a `$schema`, or with one that isn't a known dialect.
+  /// It decides which keywords identify schemas, name anchors and make references.
+  default_dialect: dialect::Dialect,
+
+  #[arg(long, value_enum, value_name = "DIALECT", default_value_t)]
+  /// The JSON Schema dialect to write bundles in. `draft-07` places embedded schemas under
+  /// `definitions` and points references at them with JSON Pointers, for validators that don't
+  /// understand `$defs` or embedded `$id`s.
+  output_dialect: dialect::OutputDialect,
+
+  #[arg(long)]
+  /// Inline every `$ref` instead of bundling, for consumers that can't follow references at all.
+  dereference: bool,
+
+  #[arg(long, conflicts_with_all = ["dereference", "root", "output_dialect"])]
+  /// Rewrite every input for JSON Schema 2020-12 instead of bundling, writing each one into
+  /// `--out-dir`, or to the output file when there's only one. Anything that can't be converted
+  /// is left as it is and reported.
+  migrate: bool,
+
+  #[arg(long, conflicts_with_all = ["dereference", "migrate", "root", "output_dialect"])]
+  /// Remove the `$defs` and `definitions` entries each input can't reach from its root instead of
+  /// bundling, writing each one into `--out-dir`, or to the output file when there's only one.
+  prune: bool,
+
+  #[arg(long, conflicts_with = "dereference")]
+  /// Keep every definition in the schemas embedded in a bundle, not just those the root can reach.
+  no_tree_shake: bool,
+
+  #[arg(
+    long,
+    value_enum,
+    value_name = "POLICY",
+    default_value_t,
+    requires = "dereference"
+  )]
+  /// What to do, with `--dereference`, about a recursive schema that can't be fully inlined.
+  on_recursion: dereference::RecursionPolicy,
+
+  #[arg(long, value_enum, value_name = "POLICY", default_value_t)]
+  /// What to do when two input files declare the same `$id` with different contents.
+  on_duplicate: bundler::DuplicatePolicy,
+
   #[arg(short=None, long)]
   /// Output debug information
   debug: bool,
@@ -27,7 +149,245 @@ fn main() {
 
   debug!("Args: {opts:#?}");
 
-  let input_details = inputs::parse_inputs(opts.input);
+  let mut input_details =
+    match inputs::parse_inputs(opts.input.clone(), &opts.exclude, opts.input_format) {
+      Ok(docs) => docs,
+      Err(e) => {
+        error!("{e}");
+        std::process::exit(1);
+      }
+    };
+  for doc in input_details.iter_mut() {
+    if let Err(e) = inputs::ensure_id(doc, opts.base_uri.as_ref(), opts.default_dialect) {
+      error!("Unable to give «{}» an `$id`: {e}", doc.source);
+      std::process::exit(1);
+    }
+  }
 
   debug!("Inputs: {input_details:#?}");
+
+  let Some(first_input) = input_details.first() else {
+    error!("No input schemas to bundle.");
+    std::process::exit(1);
+  };
+
+  let mut schemas = bundler::SchemaMap::new();
+  schemas.duplicate_policy = opts.on_duplicate;
+  schemas.default_dialect = opts.default_dialect;
+  schemas.tree_shake = !opts.no_tree_shake;
+  schemas.add_retriever(retriever::FileRetriever::new(opts.map.clone()));
+  let mode = match (opts.frozen, opts.offline, opts.fetch) {
+    (true, _, _) => Some(cache::CacheMode::Frozen),
+    (false, true, _) => Some(cache::CacheMode::Offline),
+    (false, false, true) => Some(cache::CacheMode::Online),
+    (false, false, false) => None,
+  };
+  if let Some(mode) = mode {
+    match cache::CachingRetriever::new(
+      retriever::HttpRetriever::new(),
+      &opts.cache_dir,
+      &opts.lockfile,
+      mode,
+    ) {
+      Ok(remote) => schemas.add_retriever(remote),
+      Err(e) => {
+        error!("{e}");
+        std::process::exit(1);
+      }
+    }
+  }
+  for doc in &input_details {
+    if let Err(e) = schemas.register_schema_from(doc.schema.clone(), &doc.source) {
+      error!("Unable to register «{}»: {e}", doc.source);
+      std::process::exit(1);
+    }
+  }
+
+  let style = match opts.compact {
+    true => output::OutputStyle::Compact,
+    false => output::OutputStyle::Pretty {
+      indent: opts.indent,
+    },
+  };
+
+  if opts.migrate {
+    let migrations = migrate::migrate_registered(&schemas);
+    write_each_input(
+      &opts,
+      &schemas,
+      &input_details,
+      style,
+      "Migrating",
+      "migrated",
+      |doc, item| {
+        let migration = migrations.get(&item.id.full_id)?;
+        for problem in &migration.problems {
+          warn!("Unable to fully migrate «{}» {problem}", doc.source);
+        }
+        Some(migration.schema.clone())
+      },
+    );
+    return;
+  }
+
+  if opts.prune {
+    write_each_input(
+      &opts,
+      &schemas,
+      &input_details,
+      style,
+      "Pruning",
+      "pruned",
+      |doc, item| {
+        let pruned = prune::prune(&doc.schema, item.dialect);
+        for location in &pruned.dropped {
+          info!("Dropped unreferenced «{location}» from «{}»", doc.source);
+        }
+        Some(pruned.schema)
+      },
+    );
+    return;
+  }
+
+  let roots: Vec<String> = match opts.root.is_empty() {
+    true => vec![first_input.source.clone()],
+    false => opts
+      .root
+      .iter()
+      .map(|r| match r.as_str() {
+        inputs::STDIN_INPUT => inputs::STDIN_SOURCE.to_owned(),
+        _ => r.clone(),
+      })
+      .collect(),
+  };
+  if roots.len() > 1 && opts.out_dir.is_none() {
+    error!("Bundling more than one root needs `--out-dir`.");
+    std::process::exit(1);
+  }
+
+  let root_ids: Vec<bundler::SchemaId> = roots
+    .iter()
+    .map(|root| match schemas.find_root(root) {
+      Ok(item) => item.id.clone(),
+      Err(e) => {
+        error!("{e}");
+        std::process::exit(1);
+      }
+    })
+    .collect();
+  if let Some(dir) = &opts.out_dir {
+    exit_on_path_clash(dir, &root_ids);
+  }
+
+  for (root, root_id) in roots.iter().zip(root_ids) {
+    let root_uri = root_id.full_id.as_str();
+    let (schema, unresolved, embedded) = match opts.dereference {
+      true => match schemas.dereference_from(root_uri, opts.on_recursion) {
+        Ok(d) => {
+          for location in &d.kept_refs {
+            debug!("Kept a $ref at «{location}» to break recursion");
+          }
+          (d.schema, d.unresolved, Vec::new())
+        }
+        Err(e) => {
+          error!("{e}");
+          std::process::exit(1);
+        }
+      },
+      false => match schemas.bundle_from(root_uri) {
+        Ok(b) => {
+          for cycle in &b.cycles {
+            let mut path: Vec<&str> = cycle.iter().map(|u| u.as_str()).collect();
+            path.push(path[0]);
+            warn!("Reference cycle: {}", path.join(" -> "));
+          }
+          (b.schema, b.unresolved, b.embedded)
+        }
+        Err(e) => {
+          error!("{e}");
+          std::process::exit(1);
+        }
+      },
+    };
+    for problem in &unresolved {
+      warn!("Leaving reference as-is: {problem}");
+    }
+    let schema = match opts.output_dialect {
+      dialect::OutputDialect::Draft2020_12 => schema,
+      dialect::OutputDialect::Draft07 => {
+        dialect::to_draft07(&schema, &embedded, opts.default_dialect)
+      }
+    };
+
+    let written = match &opts.out_dir {
+      Some(dir) => output::write_to_dir(&schema, dir, &root_id, style).map(|_| ()),
+      None => output::write_output(&schema, opts.output.as_deref(), style),
+    };
+    if let Err(e) = written {
+      error!("Failed to write the bundle for «{root}»: {e}");
+      std::process::exit(1);
+    }
+  }
+}
+
+/// Write every input, as `transform` rewrites it, into `--out-dir`, or to the output
+/// file when there's only one. `doing` and `done` describe the rewrite in messages,
+/// like "Migrating" and "migrated".
+fn write_each_input(
+  opts: &CliArgs,
+  schemas: &bundler::SchemaMap,
+  docs: &[inputs::InputDocument],
+  style: output::OutputStyle,
+  doing: &str,
+  done: &str,
+  transform: impl Fn(&inputs::InputDocument, &bundler::SchemaMapItem) -> Option<serde_json::Value>,
+) {
+  if docs.len() > 1 && opts.out_dir.is_none() {
+    error!("{doing} more than one input needs `--out-dir`.");
+    std::process::exit(1);
+  }
+  if let Some(dir) = &opts.out_dir {
+    exit_on_path_clash(dir, &input_ids(schemas, docs));
+  }
+  for doc in docs {
+    let Ok(item) = schemas.find_root(&doc.source) else {
+      continue;
+    };
+    let Some(schema) = transform(doc, item) else {
+      continue;
+    };
+    let written = match &opts.out_dir {
+      Some(dir) => output::write_to_dir(&schema, dir, &item.id, style).map(|_| ()),
+      None => output::write_output(&schema, opts.output.as_deref(), style),
+    };
+    if let Err(e) = written {
+      error!("Failed to write the {done} «{}»: {e}", doc.source);
+      std::process::exit(1);
+    }
+  }
+}
+
+/// The `$id` of every input document.
+fn input_ids(
+  schemas: &bundler::SchemaMap,
+  docs: &[inputs::InputDocument],
+) -> Vec<bundler::SchemaId> {
+  docs
+    .iter()
+    .filter_map(|doc| schemas.find_root(&doc.source).ok())
+    .map(|item| item.id.clone())
+    .collect()
+}
+
+/// Refuse to write two outputs to the same path in `out_dir`, where one would replace the other.
+fn exit_on_path_clash(out_dir: &std::path::Path, ids: &[bundler::SchemaId]) {
+  if let Some((path, first, second)) = output::find_path_clash(out_dir, ids) {
+    error!(
+      "«{}» and «{}» would both be written to «{}».",
+      first.full_id,
+      second.full_id,
+      path.display()
+    );
+    std::process::exit(1);
+  }
 }
@@ -1,8 +1,18 @@
-use log::{debug, error};
+use log::{debug, warn};
 use serde_json::Value as JsonValue;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
 use url::Url;
 
+use crate::dereference::{dereference, Dereferenced, RecursionPolicy};
+use crate::dialect::Dialect;
+use crate::error::BundleError;
+use crate::inputs::InputDocument;
+use crate::pointer::{decode_fragment, resolve_fragment, resolve_pointer, PointerError};
+use crate::prune::find_reachable;
+use crate::resolver::{find_anchors, find_embedded_resources, find_refs, Anchors, ResolvedRef};
+use crate::retriever::SchemaRetriever;
+
 #[derive(Debug, Clone)]
 pub struct SchemaId {
   pub full_id: Url,
@@ -21,7 +31,7 @@ impl SchemaId {
   /// # use bundle_schema::bundler::SchemaId;
   /// let json_str = serde_json::json!({"$id":"https://foo.com/somelocation/schema.json"});
   /// let result = SchemaId::from_json_value(&json_str);
-  /// assert!(result.is_some(), "Should have `Some` value");
+  /// assert!(result.is_ok(), "Should have `Ok` value");
   /// assert_eq!(
   ///   result.as_ref().unwrap().full_id.as_str(),
   ///   "https://foo.com/somelocation/schema.json",
@@ -38,41 +48,79 @@ impl SchemaId {
   ///
   /// ```rust
   /// # use bundle_schema::util::bundler::SchemaId;
+  /// # use bundle_schema::error::BundleError;
   /// let json_str = serde_json::json!({"url":"https://foo.com/somelocation/schema.json"});
   /// let result = SchemaId::from_json_value(&json_str);
-  /// assert!(result.is_none(), "Should have `None` value");
+  /// assert!(matches!(result, Err(BundleError::MissingId)), "Should have `MissingId` error");
+  ///
+  /// let json_str = serde_json::json!({"$id": 42});
+  /// let result = SchemaId::from_json_value(&json_str);
+  /// assert!(matches!(result, Err(BundleError::NonStringId(_))), "Should have `NonStringId` error");
+  ///
+  /// let json_str = serde_json::json!({"$id": "schema.json"});
+  /// let result = SchemaId::from_json_value(&json_str);
+  /// assert!(matches!(result, Err(BundleError::InvalidId { .. })), "Should have `InvalidId` error");
+  /// ```
+  pub fn from_json_value(val: &JsonValue) -> Result<Self, BundleError> {
+    Self::from_json_value_as(val, Dialect::detect(val, Dialect::default()))
+  }
+
+  /// Like [`SchemaId::from_json_value`], for a schema written in `dialect`, which
+  /// decides whether the identifier is read from `$id` or draft-04's `id`.
+  ///
+  /// # Examples
+  ///
+  /// ```rust
+  /// # use bundle_schema::bundler::SchemaId;
+  /// # use bundle_schema::dialect::Dialect;
+  /// let schema = serde_json::json!({"id": "https://foo.com/a.json"});
+  /// let id = SchemaId::from_json_value_as(&schema, Dialect::Draft04).unwrap();
+  /// assert_eq!(id.full_id.as_str(), "https://foo.com/a.json");
+  /// assert!(SchemaId::from_json_value_as(&schema, Dialect::Draft07).is_err());
   /// ```
-  pub fn from_json_value(val: &JsonValue) -> Option<Self> {
-    let id_val = match val.get("$id") {
+  pub fn from_json_value_as(val: &JsonValue, dialect: Dialect) -> Result<Self, BundleError> {
+    let id_val = match val.get(dialect.id_keyword()) {
       Some(v) => v,
       None => {
         debug!("No $id value defined: {val:#?}");
-        return None;
+        return Err(BundleError::MissingId);
       }
     };
 
     let id_val_str = match id_val.as_str() {
       Some(s) => s,
-      None => {
-        error!("Unable to parse the $id value «{id_val:#?}» as a string.");
-        return None;
-      }
+      None => return Err(BundleError::NonStringId(id_val.to_string())),
     };
 
     let id_url = match Url::parse(id_val_str) {
       Err(e) => {
-        error!("Unable to parse $id value «{id_val_str}» as URL: {e:#?}");
-        return None;
+        return Err(BundleError::InvalidId {
+          id: id_val_str.to_owned(),
+          source: e,
+        })
       }
       Ok(u) => u,
     };
 
+    Ok(Self::from_url(id_url))
+  }
+
+  /// Build a `SchemaId` from an already resolved URL.
+  ///
+  /// # Examples
+  ///
+  /// ```rust
+  /// # use bundle_schema::bundler::SchemaId;
+  /// let id = SchemaId::from_url(url::Url::parse("https://foo.com/a/b.json").unwrap());
+  /// assert_eq!(id.relative_id, "a/b.json");
+  /// ```
+  pub fn from_url(id_url: Url) -> Self {
     let relative_path = String::from(id_url.path().strip_prefix('/').unwrap_or(id_url.path()));
 
-    Some(Self {
-      full_id: id_url,
+    Self {
+      full_id: normalize_uri(&id_url),
       relative_id: relative_path,
-    })
+    }
   }
 }
 
@@ -80,11 +128,156 @@ impl SchemaId {
 pub struct SchemaMapItem {
   pub id: SchemaId,
   pub node: JsonValue,
+  pub anchors: Anchors,
+  /// The dialect the schema is written in.
+  pub dialect: Dialect,
+  /// For a resource embedded in another document, the `$id` of that top-level document.
+  pub parent: Option<Url>,
+  /// For a resource embedded in another document, the JSON Pointer to it within that document.
+  pub location: Option<String>,
+  /// Where the schema was read from, if known.
+  pub source: Option<String>,
+}
+
+impl SchemaMapItem {
+  /// Evaluate a URI fragment against this schema. The fragment can either be a
+  /// JSON Pointer or the name of an `$anchor` or `$dynamicAnchor`.
+  ///
+  /// # Examples
+  ///
+  /// ```rust
+  /// # use bundle_schema::bundler::SchemaMap;
+  /// let schema = serde_json::json!({
+  ///   "$id": "https://foo.com/common.json",
+  ///   "$defs": {"Money": {"$anchor": "money", "type": "number"}}
+  /// });
+  /// let mut registry = SchemaMap::new();
+  /// registry.register_schema(schema).unwrap();
+  /// let item = registry.get_item("common.json").unwrap();
+  ///
+  /// assert_eq!(item.resolve_fragment("/$defs/Money").unwrap()["type"], "number");
+  /// assert_eq!(item.resolve_fragment("money").unwrap()["type"], "number");
+  /// assert!(item.resolve_fragment("/$defs/Currency").is_err());
+  /// assert!(item.resolve_fragment("currency").is_err());
+  /// ```
+  pub fn resolve_fragment(&self, fragment: &str) -> Result<&JsonValue, PointerError> {
+    resolve_in_resource(&self.node, &self.anchors, fragment)
+  }
+}
+
+/// Resolve a fragment, either a JSON Pointer or a plain anchor name, against a schema
+/// resource whose anchors are `anchors`.
+pub(crate) fn resolve_in_resource<'a>(
+  node: &'a JsonValue,
+  anchors: &Anchors,
+  fragment: &str,
+) -> Result<&'a JsonValue, PointerError> {
+  if fragment.is_empty() || fragment.starts_with('/') {
+    return resolve_fragment(node, fragment);
+  }
+
+  let name = decode_fragment(fragment)?;
+  match anchors.plain.get(&name) {
+    Some(pointer) => resolve_pointer(node, pointer),
+    None => Err(PointerError::UnknownAnchor(name)),
+  }
+}
+
+/// Normalize a URI for use as a registry key.
+///
+/// Parsing with the `url` crate already folds the case of the scheme and host,
+/// drops default ports and removes dot-segments. On top of that, an empty fragment
+/// is stripped, since `https://foo.com/a.json#` names the same resource as
+/// `https://foo.com/a.json`.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::bundler::normalize_uri;
+/// # use url::Url;
+/// let url = Url::parse("HTTPS://Foo.COM:443/schemas/./v1/../address.json#").unwrap();
+/// assert_eq!(normalize_uri(&url).as_str(), "https://foo.com/schemas/address.json");
+/// ```
+pub fn normalize_uri(url: &Url) -> Url {
+  let mut normalized = url.clone();
+  if normalized.fragment() == Some("") {
+    normalized.set_fragment(None);
+  }
+  normalized
+}
+
+/// A key to look up a registered schema by: either its absolute URI or, as a
+/// fallback, the path-only `relative_id`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaKey {
+  Uri(Url),
+  Relative(String),
+}
+
+impl From<&Url> for SchemaKey {
+  fn from(url: &Url) -> Self {
+    SchemaKey::Uri(url.clone())
+  }
+}
+
+impl From<Url> for SchemaKey {
+  fn from(url: Url) -> Self {
+    SchemaKey::Uri(url)
+  }
+}
+
+impl From<&str> for SchemaKey {
+  /// Strings that parse as absolute URIs are treated as such; anything else is a `relative_id`.
+  fn from(which: &str) -> Self {
+    match Url::parse(which) {
+      Ok(url) => SchemaKey::Uri(url),
+      Err(_) => SchemaKey::Relative(which.trim_start_matches('/').to_owned()),
+    }
+  }
+}
+
+impl From<String> for SchemaKey {
+  fn from(which: String) -> Self {
+    SchemaKey::from(which.as_str())
+  }
+}
+
+impl From<&String> for SchemaKey {
+  fn from(which: &String) -> Self {
+    SchemaKey::from(which.as_str())
+  }
+}
+
+/// What to do when two different schemas declare the same `$id`.
+///
+/// Identical schemas declaring the same `$id` are always harmless, and are never
+/// treated as a conflict.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum DuplicatePolicy {
+  /// Keep the schema registered first and ignore the later one.
+  FirstWins,
+  /// Replace the earlier schema with the later one.
+  LastWins,
+  /// Refuse to register the later schema.
+  #[default]
+  Fail,
 }
 
 #[derive(Debug)]
 pub struct SchemaMap {
-  pub registry: HashMap<String, SchemaMapItem>,
+  /// Registered schemas, keyed by their normalized absolute URI.
+  pub registry: HashMap<Url, SchemaMapItem>,
+  /// Secondary index from `relative_id` to every URI registered with that path.
+  pub relative_index: HashMap<String, Vec<Url>>,
+  /// How to handle two different schemas declaring the same `$id`.
+  pub duplicate_policy: DuplicatePolicy,
+  /// Where to look, in order, for referenced schemas that haven't been registered.
+  pub retrievers: Vec<Box<dyn SchemaRetriever>>,
+  /// The dialect of schemas that don't name a known one with `$schema`.
+  pub default_dialect: Dialect,
+  /// Whether bundling leaves out definitions in embedded resources that the root
+  /// can't reach.
+  pub tree_shake: bool,
 }
 
 // Fine, clippy, I'll implement Default for SchemaMap.
@@ -97,11 +290,98 @@ impl SchemaMap {
   pub fn new() -> Self {
     SchemaMap {
       registry: HashMap::new(),
+      relative_index: HashMap::new(),
+      duplicate_policy: DuplicatePolicy::default(),
+      retrievers: Vec::new(),
+      default_dialect: Dialect::default(),
+      tree_shake: true,
+    }
+  }
+
+  /// Consult `retriever` for unregistered schemas, after any retrievers added before it.
+  pub fn add_retriever(&mut self, retriever: impl SchemaRetriever + 'static) {
+    self.retrievers.push(Box::new(retriever));
+  }
+
+  /// Ask each retriever in turn for the schema at `uri`, giving it `uri` as its `$id`
+  /// if it lacks one.
+  fn retrieve(&self, uri: &Url) -> Result<Option<InputDocument>, BundleError> {
+    for retriever in &self.retrievers {
+      if let Some(mut doc) = retriever.retrieve(uri)? {
+        let id_keyword = Dialect::detect(&doc.schema, self.default_dialect).id_keyword();
+        if let Some(schema) = doc.schema.as_object_mut() {
+          schema
+            .entry(id_keyword)
+            .or_insert_with(|| JsonValue::String(uri.to_string()));
+        }
+        return Ok(Some(doc));
+      }
+    }
+    Ok(None)
+  }
+
+  /// Decide whether `item` should be inserted, given anything already registered under its id.
+  fn should_insert(&self, item: &SchemaMapItem) -> Result<bool, BundleError> {
+    let Some(existing) = self.registry.get(&item.id.full_id) else {
+      return Ok(true);
+    };
+
+    if existing.node == item.node {
+      debug!(
+        "«{}» is registered again with identical contents; ignoring the duplicate.",
+        item.id.full_id
+      );
+      return Ok(false);
+    }
+
+    let conflict = BundleError::DuplicateId {
+      id: item.id.full_id.to_string(),
+      first: existing.source.clone(),
+      second: item.source.clone(),
+    };
+    match self.duplicate_policy {
+      DuplicatePolicy::FirstWins => {
+        warn!("{conflict}; keeping the first one.");
+        Ok(false)
+      }
+      DuplicatePolicy::LastWins => {
+        warn!("{conflict}; keeping the last one.");
+        Ok(true)
+      }
+      DuplicatePolicy::Fail => Err(conflict),
+    }
+  }
+
+  fn insert_item(&mut self, item: SchemaMapItem) {
+    let key = item.id.full_id.clone();
+    let urls = self
+      .relative_index
+      .entry(item.id.relative_id.clone())
+      .or_default();
+    if !urls.contains(&key) {
+      urls.push(key.clone());
+    }
+
+    self.registry.insert(key, item);
+  }
+
+  fn remove_item(&mut self, id: &Url) {
+    let Some(item) = self.registry.remove(id) else {
+      return;
+    };
+    if let Some(urls) = self.relative_index.get_mut(&item.id.relative_id) {
+      urls.retain(|url| url != id);
+      if urls.is_empty() {
+        self.relative_index.remove(&item.id.relative_id);
+      }
     }
   }
 
   /// Register a schema with the bundler
   ///
+  /// Subschemas that declare their own `$id` are registered too, each under its
+  /// resolved id and pointing back at the document they were found in.
+  ///
   /// # Examples
   ///
   /// ```rust
@@ -111,28 +391,142 @@ impl SchemaMap {
   ///   "description":"I'm just a schema"
   /// });
   /// let mut registry = SchemaMap::new();
-  /// registry.register_schema(schema);
+  /// registry.register_schema(schema).unwrap();
   /// assert_eq!(registry.registry.len(), 1, "Verify we've got an item.");
   /// ```
-  pub fn register_schema(&mut self, schema: JsonValue) {
-    let id = SchemaId::from_json_value(&schema);
+  ///
+  /// Registering a document with embedded resources
+  ///
+  /// ```rust
+  /// # use bundle_schema::bundler::SchemaMap;
+  /// let schema = serde_json::json!({
+  ///   "$id": "https://foo.com/somelocation/schema.json",
+  ///   "$defs": {"inner": {"$id": "inner.json", "type": "string"}}
+  /// });
+  /// let mut registry = SchemaMap::new();
+  /// registry.register_schema(schema).unwrap();
+  ///
+  /// let inner = registry.get_item("somelocation/inner.json").unwrap();
+  /// assert_eq!(inner.node["type"], "string");
+  /// assert_eq!(inner.parent.as_ref().unwrap().as_str(), "https://foo.com/somelocation/schema.json");
+  /// assert_eq!(inner.location.as_deref(), Some("/$defs/inner"));
+  /// ```
+  pub fn register_schema(&mut self, schema: JsonValue) -> Result<(), BundleError> {
+    self.register(schema, None)
+  }
+
+  /// Register a schema with the bundler, noting where it was read from.
+  ///
+  /// When a different schema has already been registered under the same `$id`,
+  /// the [`DuplicatePolicy`] decides what happens. The error names both sources. The
+  /// policy is applied to the document as a whole: its embedded resources are kept or
+  /// ignored along with it, and those of a document it replaces are forgotten.
+  ///
+  /// # Examples
+  ///
+  /// ```rust
+  /// # use bundle_schema::bundler::{DuplicatePolicy, SchemaMap};
+  /// let mut registry = SchemaMap::new();
+  /// let schema = serde_json::json!({"$id": "https://foo.com/a.json", "type": "string"});
+  /// registry.register_schema_from(schema.clone(), "a.json").unwrap();
+  /// registry.register_schema_from(schema, "copy-of-a.json").unwrap();
+  ///
+  /// let other = serde_json::json!({"$id": "https://foo.com/a.json", "type": "number"});
+  /// let err = registry.register_schema_from(other.clone(), "other.json").unwrap_err();
+  /// assert_eq!(
+  ///   err.to_string(),
+  ///   "$id «https://foo.com/a.json» is declared with different contents in «a.json» and «other.json»"
+  /// );
+  ///
+  /// registry.duplicate_policy = DuplicatePolicy::LastWins;
+  /// registry.register_schema_from(other.clone(), "other.json").unwrap();
+  /// assert_eq!(registry.get("https://foo.com/a.json"), Some(&other));
+  /// ```
+  pub fn register_schema_from(
+    &mut self,
+    schema: JsonValue,
+    source: &str,
+  ) -> Result<(), BundleError> {
+    self.register(schema, Some(source.to_owned()))
+  }
 
-    if let Some(the_id) = id {
-      debug!("Using ID {the_id:#?}");
+  fn register(&mut self, schema: JsonValue, source: Option<String>) -> Result<(), BundleError> {
+    let dialect = Dialect::detect(&schema, self.default_dialect);
+    let the_id = SchemaId::from_json_value_as(&schema, dialect)?;
+    debug!("Using ID {the_id:#?}");
+    if let Some(uri) = schema.get("$schema").and_then(JsonValue::as_str) {
+      if Dialect::from_schema_uri(uri).is_none() {
+        warn!(
+          "Unknown `$schema` «{uri}» in «{}»; treating it as {dialect:?}",
+          the_id.full_id
+        );
+      }
+    }
 
-      self.registry.insert(
-        the_id.relative_id.clone(),
-        SchemaMapItem {
-          id: the_id,
-          node: schema,
-        },
+    let mut items = Vec::new();
+    for embedded in find_embedded_resources(&schema, &the_id.full_id, dialect) {
+      let Ok(node) = resolve_pointer(&schema, &embedded.location) else {
+        continue;
+      };
+      debug!(
+        "Registering embedded resource «{}» at «{}»",
+        embedded.id, embedded.location
       );
-    } else {
-      error!("Unable to register a schema without `$id` property.");
+
+      items.push(SchemaMapItem {
+        id: SchemaId::from_url(embedded.id),
+        anchors: find_anchors(node, embedded.dialect),
+        dialect: embedded.dialect,
+        node: node.clone(),
+        parent: Some(the_id.full_id.clone()),
+        location: Some(embedded.location),
+        source: source.clone(),
+      });
     }
+    let document = SchemaMapItem {
+      id: the_id,
+      anchors: find_anchors(&schema, dialect),
+      dialect,
+      node: schema,
+      parent: None,
+      location: None,
+      source,
+    };
+
+    // The document's embedded resources stand or fall with it, so the resources they
+    // point back at are always the ones registered.
+    if !self.should_insert(&document)? {
+      return Ok(());
+    }
+    let replaced: Vec<Url> = self
+      .registry
+      .iter()
+      .filter(|(id, item)| {
+        **id == document.id.full_id || item.parent.as_ref() == Some(&document.id.full_id)
+      })
+      .map(|(id, _)| id.clone())
+      .collect();
+
+    // Check every resource before registering any, so a conflict doesn't leave a
+    // document half registered.
+    let mut accepted = Vec::with_capacity(items.len() + 1);
+    for item in items {
+      if replaced.contains(&item.id.full_id) || self.should_insert(&item)? {
+        accepted.push(item);
+      }
+    }
+    accepted.push(document);
+    for id in replaced {
+      self.remove_item(&id);
+    }
+    for item in accepted {
+      self.insert_item(item);
+    }
+
+    Ok(())
   }
 
-  /// Get an item from the registry by its relative ID
+  /// Get a schema from the registry, either by its absolute URI or by its relative ID
   ///
   /// # Examples
   ///
@@ -145,11 +539,27 @@ impl SchemaMap {
   /// #   "description":"I'm just a schema"
   /// # });
   /// # let mut registry = SchemaMap::new();
-  /// # registry.register_schema(schema.clone());
+  /// # registry.register_schema(schema.clone()).unwrap();
   /// let item = registry.get("somelocation/schema.json".to_owned());
   /// assert_eq!(item.unwrap(), &schema, "We got back the schema we registered!");
   /// ```
   ///
+  /// Looking up by URI
+  ///
+  /// ```rust
+  /// # use bundle_schema::bundler::SchemaMap;
+  /// # let schema = serde_json::json!({
+  /// #   "$id":"https://foo.com/somelocation/schema.json",
+  /// #   "description":"I'm just a schema"
+  /// # });
+  /// # let mut registry = SchemaMap::new();
+  /// # registry.register_schema(schema.clone()).unwrap();
+  /// let url = url::Url::parse("https://FOO.com/somelocation/schema.json").unwrap();
+  /// assert_eq!(registry.get(&url), Some(&schema));
+  /// assert_eq!(registry.get("https://foo.com/somelocation/schema.json"), Some(&schema));
+  /// assert_eq!(registry.get("https://bar.com/somelocation/schema.json"), None);
+  /// ```
+  ///
   /// Simple record-not-found case
   ///
   /// ```rust
@@ -159,21 +569,615 @@ impl SchemaMap {
   /// #   "description":"I'm just a schema"
   /// # });
   /// # let mut registry = SchemaMap::new();
-  /// # registry.register_schema(schema.clone());
+  /// # registry.register_schema(schema.clone()).unwrap();
   /// let item = registry.get("somelocation/missing-value.json".to_owned());
   /// assert!(item.is_none(), "Expected None");
   /// ```
-  pub fn get(&self, which: String) -> Option<&JsonValue> {
-    if let Some(item) = self.registry.get(&which) {
-      return Some(&item.node);
+  pub fn get<K: Into<SchemaKey>>(&self, which: K) -> Option<&JsonValue> {
+    self.get_item(which).map(|item| &item.node)
+  }
+
+  /// Get a registered item, either by its absolute URI or by its relative ID.
+  ///
+  /// Any fragment on a URI is ignored. A relative ID shared by schemas from several
+  /// hosts is ambiguous, so nothing is returned for it.
+  ///
+  /// # Examples
+  ///
+  /// ```rust
+  /// # use bundle_schema::bundler::SchemaMap;
+  /// let mut registry = SchemaMap::new();
+  /// registry.register_schema(serde_json::json!({"$id": "https://a.com/x.json", "title": "a"})).unwrap();
+  /// registry.register_schema(serde_json::json!({"$id": "https://b.com/x.json", "title": "b"})).unwrap();
+  ///
+  /// assert_eq!(registry.registry.len(), 2);
+  /// assert_eq!(registry.get_item("https://a.com/x.json#/title").unwrap().node["title"], "a");
+  /// assert_eq!(registry.get_item("https://b.com/x.json").unwrap().node["title"], "b");
+  /// assert!(registry.get_item("x.json").is_none());
+  /// ```
+  pub fn get_item<K: Into<SchemaKey>>(&self, which: K) -> Option<&SchemaMapItem> {
+    match which.into() {
+      SchemaKey::Uri(url) => {
+        let mut key = normalize_uri(&url);
+        key.set_fragment(None);
+        self.registry.get(&key)
+      }
+      SchemaKey::Relative(relative) => match self.relative_index.get(&relative)?.as_slice() {
+        [only] => self.registry.get(only),
+        [] => None,
+        several => {
+          warn!("The relative id «{relative}» is ambiguous; it could be any of {several:?}");
+          None
+        }
+      },
     }
+  }
 
-    None
+  /// Find the registered document to use as a root schema.
+  ///
+  /// `root` can be the file a document was read from, its `$id`, or its relative
+  /// ID. It's an error if nothing matches, or if several documents do.
+  ///
+  /// # Examples
+  ///
+  /// ```rust
+  /// # use bundle_schema::bundler::SchemaMap;
+  /// # use bundle_schema::error::BundleError;
+  /// let mut registry = SchemaMap::new();
+  /// let a = serde_json::json!({"$id": "https://a.com/api/order.json"});
+  /// let b = serde_json::json!({"$id": "https://b.com/api/order.json"});
+  /// registry.register_schema_from(a.clone(), "schemas/a/order.json").unwrap();
+  /// registry.register_schema_from(b.clone(), "schemas/b/order.json").unwrap();
+  ///
+  /// assert_eq!(registry.find_root("schemas/a/order.json").unwrap().node, a);
+  /// assert_eq!(registry.find_root("https://b.com/api/order.json").unwrap().node, b);
+  /// assert!(matches!(registry.find_root("api/order.json"), Err(BundleError::AmbiguousRoot { .. })));
+  /// assert!(matches!(registry.find_root("invoice.json"), Err(BundleError::RootNotFound(_))));
+  /// ```
+  pub fn find_root(&self, root: &str) -> Result<&SchemaMapItem, BundleError> {
+    let wanted_path = Path::new(root).canonicalize().ok();
+    let mut candidates: BTreeSet<&Url> = self
+      .registry
+      .values()
+      .filter(|item| item.parent.is_none())
+      .filter(|item| {
+        item.source.as_deref().is_some_and(|source| {
+          source == root
+            || wanted_path.is_some() && Path::new(source).canonicalize().ok() == wanted_path
+        })
+      })
+      .map(|item| &item.id.full_id)
+      .collect();
+
+    match SchemaKey::from(root) {
+      SchemaKey::Uri(url) => {
+        if let Some(item) = self.get_item(&url) {
+          candidates.insert(&item.id.full_id);
+        }
+      }
+      SchemaKey::Relative(relative) => {
+        candidates.extend(self.relative_index.get(&relative).into_iter().flatten());
+      }
+    }
+
+    let mut candidates = candidates.into_iter();
+    match (candidates.next(), candidates.next()) {
+      (Some(only), None) => Ok(&self.registry[only]),
+      (None, _) => Err(BundleError::RootNotFound(root.to_owned())),
+      (Some(first), Some(second)) => Err(BundleError::AmbiguousRoot {
+        root: root.to_owned(),
+        candidates: [first, second]
+          .into_iter()
+          .chain(candidates)
+          .map(Url::to_string)
+          .collect(),
+      }),
+    }
+  }
+
+  /// Register every schema reachable from `root` that isn't registered yet, loading
+  /// each one through the [`SchemaMap::retrievers`]. Returns the URIs that were loaded.
+  ///
+  /// References that no retriever can load are left for [`bundle`] to report. With
+  /// [`SchemaMap::tree_shake`] set, references are only followed out of the definitions
+  /// the root can reach, so definitions that bundling leaves out aren't loaded from.
+  ///
+  /// # Examples
+  ///
+  /// ```rust
+  /// # use bundle_schema::bundler::SchemaMap;
+  /// # use bundle_schema::retriever::MemoryRetriever;
+  /// # use url::Url;
+  /// let mut store = MemoryRetriever::new();
+  /// store.insert(
+  ///   Url::parse("https://schemas.example.com/types/money.json").unwrap(),
+  ///   serde_json::json!({"$ref": "currency.json"}),
+  /// );
+  /// store.insert(
+  ///   Url::parse("https://schemas.example.com/types/currency.json").unwrap(),
+  ///   serde_json::json!({"type": "string"}),
+  /// );
+  ///
+  /// let mut registry = SchemaMap::new();
+  /// registry.add_retriever(store);
+  /// let root = serde_json::json!({
+  ///   "$id": "https://schemas.example.com/order.json",
+  ///   "properties": {"total": {"$ref": "types/money.json"}}
+  /// });
+  /// registry.register_schema(root).unwrap();
+  ///
+  /// let root_id = Url::parse("https://schemas.example.com/order.json").unwrap();
+  /// let loaded = registry.load_reachable(&root_id).unwrap();
+  /// assert_eq!(loaded.len(), 2);
+  /// assert_eq!(
+  ///   registry.get("https://schemas.example.com/types/currency.json").unwrap()["type"],
+  ///   "string"
+  /// );
+  /// ```
+  pub fn load_reachable(&mut self, root: &Url) -> Result<Vec<Url>, BundleError> {
+    if !self.tree_shake {
+      return self.load_referenced(root);
+    }
+
+    let mut loaded = Vec::new();
+    let mut tried = BTreeSet::new();
+    while let Some(item) = self.get_item(root) {
+      let missing: Vec<Url> = find_reachable(&item.node, self)
+        .unregistered
+        .into_iter()
+        .filter(|uri| tried.insert(uri.clone()))
+        .collect();
+      if missing.is_empty() {
+        break;
+      }
+      for uri in missing {
+        // A document loaded earlier in this round may have brought it along.
+        if self.get_item(&uri).is_some() {
+          continue;
+        }
+        if let Some(doc) = self.retrieve(&uri)? {
+          self.register_schema_from(doc.schema, &doc.source)?;
+          loaded.push(uri);
+        }
+      }
+    }
+
+    Ok(loaded)
+  }
+
+  /// Like [`SchemaMap::load_reachable`], following every reference in each document.
+  fn load_referenced(&mut self, root: &Url) -> Result<Vec<Url>, BundleError> {
+    let mut loaded = Vec::new();
+    let mut visited = BTreeSet::new();
+    let mut pending = vec![normalize_uri(root)];
+
+    while let Some(uri) = pending.pop() {
+      if !visited.insert(uri.clone()) {
+        continue;
+      }
+      let Some(item) = self.get_item(&uri) else {
+        continue;
+      };
+
+      let mut targets = Vec::new();
+      for ResolvedRef { mut target, .. } in
+        find_refs(&item.node, Some(&item.id.full_id), item.dialect)
+      {
+        target.set_fragment(None);
+        targets.push(target);
+      }
+
+      for target in targets {
+        if self.get_item(&target).is_none() {
+          if let Some(doc) = self.retrieve(&target)? {
+            self.register_schema_from(doc.schema, &doc.source)?;
+            loaded.push(target.clone());
+          }
+        }
+        pending.push(target);
+      }
+    }
+
+    Ok(loaded)
+  }
+
+  /// Bundle the registered document chosen by [`SchemaMap::find_root`].
+  ///
+  /// Other registered schemas are only embedded when they're reachable from it.
+  /// Unregistered schemas it refers to are loaded first, with [`SchemaMap::load_reachable`].
+  ///
+  /// # Examples
+  ///
+  /// ```rust
+  /// # use bundle_schema::bundler::SchemaMap;
+  /// let mut registry = SchemaMap::new();
+  /// let root = serde_json::json!({"$id": "https://foo.com/root.json", "$ref": "used.json"});
+  /// registry.register_schema_from(root, "root.json").unwrap();
+  /// registry.register_schema(serde_json::json!({"$id": "https://foo.com/used.json"})).unwrap();
+  /// registry.register_schema(serde_json::json!({"$id": "https://foo.com/unused.json"})).unwrap();
+  ///
+  /// let bundled = registry.bundle_from("root.json").unwrap();
+  /// let defs = bundled.schema["$defs"].as_object().unwrap();
+  /// assert_eq!(defs.keys().collect::<Vec<_>>(), vec!["https://foo.com/used.json"]);
+  /// ```
+  pub fn bundle_from(&mut self, root: &str) -> Result<Bundle, BundleError> {
+    let root_id = self.find_root(root)?.id.full_id.clone();
+    self.load_reachable(&root_id)?;
+
+    match self.get_item(&root_id) {
+      Some(item) => bundle(&item.node, self),
+      None => Err(BundleError::RootNotFound(root.to_owned())),
+    }
+  }
+
+  /// Dereference the registered document chosen by [`SchemaMap::find_root`], inlining
+  /// every reference it makes. See [`dereference`].
+  ///
+  /// Unregistered schemas it refers to are loaded first, with [`SchemaMap::load_reachable`].
+  pub fn dereference_from(
+    &mut self,
+    root: &str,
+    policy: RecursionPolicy,
+  ) -> Result<Dereferenced, BundleError> {
+    let root_id = self.find_root(root)?.id.full_id.clone();
+    self.load_reachable(&root_id)?;
+
+    match self.get_item(&root_id) {
+      Some(item) => dereference(&item.node, self, policy),
+      None => Err(BundleError::RootNotFound(root.to_owned())),
+    }
+  }
+}
+
+/// The result of bundling a root schema.
+#[derive(Debug)]
+pub struct Bundle {
+  /// The compound document.
+  pub schema: JsonValue,
+  /// Every reference cycle between resources, each one listed in the order its
+  /// references are followed, starting from its lowest URI.
+  pub cycles: Vec<Vec<Url>>,
+  /// References that were left as they are because they couldn't be followed:
+  /// [`BundleError::UnresolvedRef`] and [`BundleError::BrokenRef`].
+  pub unresolved: Vec<BundleError>,
+  /// The `$defs` keys of the resources that were embedded, which are also their `$id`s.
+  pub embedded: Vec<String>,
+}
+
+/// Bundle a root schema and every external resource it references into a single
+/// JSON Schema 2020-12 compound document.
+///
+/// Each `$ref` is resolved against the nearest enclosing `$id`. Every referenced
+/// resource found in `schemas` is embedded, with its `$id` intact, under the root's
+/// `$defs` keyed by its full id. Embedded resources are walked in turn, so
+/// transitive references are pulled in as well. A reference to a
+/// resource embedded in another document embeds that whole document. References that
+/// can't be found in `schemas` are left as they are, and fragments (JSON Pointers or
+/// anchor names) are checked against the resource they point into. Both kinds of
+/// problem are listed in [`Bundle::unresolved`].
+///
+/// With [`SchemaMap::tree_shake`] set, as it is by default, the definitions in
+/// embedded resources that the root can't reach through a chain of references are left
+/// out, along with any resources only they refer to, and the references inside them
+/// are neither reported nor counted towards cycles. See
+/// [`prune`](crate::prune::prune). The root's own definitions are always kept.
+///
+/// Bundling fails only if resources need embedding and the root schema, or its
+/// `$defs`, isn't an object.
+///
+/// Each resource is embedded only once, so recursive and mutually recursive schemas
+/// are safe: the cyclic `$ref`s are left intact and the cycles are listed in the
+/// returned [`Bundle`]. A resource referring to itself counts as a cycle.
+///
+/// Targets of `$dynamicRef` are embedded just like `$ref` targets. Since embedded
+/// resources keep their `$id` and any `$dynamicAnchor`s, each one remains a separate
+/// resource and the dynamic scope seen during evaluation is unchanged.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::bundler::{bundle, SchemaMap};
+/// let root = serde_json::json!({
+///   "$id": "https://foo.com/root.json",
+///   "properties": {"address": {"$ref": "common/address.json"}}
+/// });
+/// let address = serde_json::json!({
+///   "$id": "https://foo.com/common/address.json",
+///   "type": "object"
+/// });
+/// let mut registry = SchemaMap::new();
+/// registry.register_schema(root.clone()).unwrap();
+/// registry.register_schema(address.clone()).unwrap();
+///
+/// let bundled = bundle(&root, &registry).unwrap().schema;
+/// assert_eq!(bundled["$defs"]["https://foo.com/common/address.json"], address);
+/// assert_eq!(bundled["properties"]["address"]["$ref"], "common/address.json");
+/// ```
+///
+/// Anchors and `$dynamicRef` targets.
+///
+/// ```rust
+/// # use bundle_schema::bundler::{bundle, SchemaMap};
+/// let root = serde_json::json!({
+///   "$id": "https://foo.com/root.json",
+///   "properties": {
+///     "street": {"$ref": "address.json#street"},
+///     "children": {"$dynamicRef": "tree.json#node"}
+///   }
+/// });
+/// let address = serde_json::json!({
+///   "$id": "https://foo.com/address.json",
+///   "properties": {"street": {"$anchor": "street", "type": "string"}}
+/// });
+/// let tree = serde_json::json!({
+///   "$id": "https://foo.com/tree.json",
+///   "$dynamicAnchor": "node",
+///   "items": {"$dynamicRef": "#node"}
+/// });
+/// let mut registry = SchemaMap::new();
+/// for schema in [&root, &address, &tree] {
+///   registry.register_schema(schema.clone()).unwrap();
+/// }
+///
+/// let bundled = bundle(&root, &registry).unwrap();
+/// assert_eq!(bundled.schema["$defs"]["https://foo.com/address.json"], address);
+/// assert_eq!(bundled.schema["$defs"]["https://foo.com/tree.json"], tree);
+/// assert_eq!(bundled.cycles.len(), 1, "tree.json refers to itself");
+/// ```
+pub fn bundle(root: &JsonValue, schemas: &SchemaMap) -> Result<Bundle, BundleError> {
+  let root_dialect = Dialect::detect(root, schemas.default_dialect);
+  let root_item = SchemaId::from_json_value_as(root, root_dialect)
+    .ok()
+    .map(|id| SchemaMapItem {
+      id,
+      anchors: find_anchors(root, root_dialect),
+      dialect: root_dialect,
+      node: root.clone(),
+      parent: None,
+      location: None,
+      source: None,
+    });
+  let root_id = root_item.as_ref().map(|item| &item.id.full_id);
+
+  let mut embedded: BTreeMap<String, JsonValue> = BTreeMap::new();
+  let mut edges: BTreeMap<Url, BTreeSet<Url>> = BTreeMap::new();
+  let mut unresolved = Vec::new();
+  let mut pending: Vec<(Option<&Url>, &JsonValue, Dialect)> = vec![(root_id, root, root_dialect)];
+  let reachable = schemas.tree_shake.then(|| find_reachable(root, schemas));
+
+  while let Some((base, node, dialect)) = pending.pop() {
+    let refs = match &reachable {
+      Some(reachable) => reachable.refs(base),
+      None => find_refs(node, base, dialect),
+    };
+    for ResolvedRef {
+      location,
+      reference,
+      dynamic,
+      mut target,
+    } in refs
+    {
+      let keyword = match dynamic {
+        true => dialect.dynamic_ref_keyword().unwrap_or("$ref"),
+        false => "$ref",
+      };
+      let fragment = target.fragment().map(str::to_owned);
+      target.set_fragment(None);
+
+      let resource = match &root_item {
+        Some(item) if item.id.full_id == target => item,
+        _ => match schemas.get_item(&target) {
+          Some(item) => {
+            // A resource embedded in another document comes along with that whole document.
+            let document = match &item.parent {
+              Some(parent) if Some(parent) == root_id => None,
+              Some(parent) => schemas.get_item(parent),
+              None => Some(item),
+            };
+            if let Some(document) = document {
+              let key = document.id.full_id.as_str();
+              if !embedded.contains_key(key) {
+                debug!("Embedding «{key}»");
+                let mut node = match &reachable {
+                  Some(reachable) => {
+                    let pruned = reachable.prune(&document.id.full_id, &document.node);
+                    for location in &pruned.dropped {
+                      debug!("Leaving out unreachable «{location}» from «{key}»");
+                    }
+                    pruned.schema
+                  }
+                  None => document.node.clone(),
+                };
+                // An embedded resource written in another dialect has to say so.
+                if document.dialect != root_dialect {
+                  if let Some(obj) = node.as_object_mut() {
+                    obj.entry("$schema").or_insert_with(|| {
+                      JsonValue::String(document.dialect.schema_uri().to_owned())
+                    });
+                  }
+                }
+                embedded.insert(key.to_owned(), node);
+                pending.push((Some(&document.id.full_id), &document.node, document.dialect));
+              }
+            }
+            item
+          }
+          None => {
+            debug!(
+              "No registered schema for {keyword} «{reference}» ({target}); leaving it as-is."
+            );
+            unresolved.push(BundleError::UnresolvedRef {
+              reference,
+              location,
+              target: target.to_string(),
+            });
+            continue;
+          }
+        },
+      };
+
+      let points_at = match fragment.as_deref() {
+        None | Some("") => Some(&resource.node),
+        Some(fragment) => match resource.resolve_fragment(fragment) {
+          Ok(n) => Some(n),
+          Err(e) => {
+            unresolved.push(BundleError::BrokenRef {
+              reference,
+              location,
+              source: Box::new(e),
+            });
+            None
+          }
+        },
+      };
+
+      // Pointing somewhere inside the same resource isn't a cycle, but pointing at its root is.
+      if let Some(source) = base {
+        if source != &target || points_at.is_some_and(|n| std::ptr::eq(n, &resource.node)) {
+          edges
+            .entry(source.clone())
+            .or_default()
+            .insert(target.clone());
+        }
+      }
+    }
+  }
+
+  let cycles = find_cycles(&edges);
+  let mut schema = root.clone();
+  let embedded = embed_resources(&mut schema, embedded)?;
+
+  Ok(Bundle {
+    schema,
+    cycles,
+    unresolved,
+    embedded,
+  })
+}
+
+/// Place embedded resources under the root schema's `$defs`, returning the keys of
+/// those that were placed.
+fn embed_resources(
+  bundled: &mut JsonValue,
+  embedded: BTreeMap<String, JsonValue>,
+) -> Result<Vec<String>, BundleError> {
+  if embedded.is_empty() {
+    return Ok(Vec::new());
   }
+
+  let Some(root_obj) = bundled.as_object_mut() else {
+    return Err(BundleError::UnembeddableRoot(String::from(
+      "the root schema isn't an object",
+    )));
+  };
+  let defs = root_obj
+    .entry("$defs")
+    .or_insert_with(|| JsonValue::Object(Default::default()));
+  let Some(defs) = defs.as_object_mut() else {
+    return Err(BundleError::UnembeddableRoot(String::from(
+      "the root schema's `$defs` isn't an object",
+    )));
+  };
+
+  let mut placed = Vec::new();
+  for (key, resource) in embedded {
+    if defs.contains_key(&key) {
+      warn!("The root schema already defines `$defs` entry «{key}»; not overwriting it.");
+      continue;
+    }
+    defs.insert(key.clone(), resource);
+    placed.push(key);
+  }
+
+  Ok(placed)
+}
+
+/// Find the cycles in a graph of references between resources.
+///
+/// Every elementary cycle is listed, found with Johnson's algorithm: the search from
+/// each URI only visits URIs above it, so each cycle is found once, starting from its
+/// lowest URI.
+fn find_cycles(edges: &BTreeMap<Url, BTreeSet<Url>>) -> Vec<Vec<Url>> {
+  struct Search<'a> {
+    edges: &'a BTreeMap<Url, BTreeSet<Url>>,
+    start: &'a Url,
+    stack: Vec<&'a Url>,
+    blocked: BTreeSet<&'a Url>,
+    /// The URIs to unblock along with each one, since they only stalled on it.
+    waiting: BTreeMap<&'a Url, BTreeSet<&'a Url>>,
+    cycles: BTreeSet<Vec<Url>>,
+  }
+
+  impl<'a> Search<'a> {
+    fn next(&self, node: &'a Url) -> impl Iterator<Item = &'a Url> + 'a {
+      let start = self.start;
+      let edges = self.edges;
+      edges
+        .get(node)
+        .into_iter()
+        .flatten()
+        .filter(move |next| *next >= start)
+    }
+
+    /// Whether any cycle runs through `node` from the stack.
+    fn visit(&mut self, node: &'a Url) -> bool {
+      let mut found = false;
+      self.stack.push(node);
+      self.blocked.insert(node);
+      for next in self.next(node).collect::<Vec<_>>() {
+        if next == self.start {
+          let cycle = self.stack.iter().map(|n| (*n).clone()).collect();
+          self.cycles.insert(cycle);
+          found = true;
+        } else if !self.blocked.contains(next) && self.visit(next) {
+          found = true;
+        }
+      }
+      if found {
+        self.unblock(node);
+      } else {
+        for next in self.next(node).collect::<Vec<_>>() {
+          self.waiting.entry(next).or_default().insert(node);
+        }
+      }
+      self.stack.pop();
+      found
+    }
+
+    fn unblock(&mut self, node: &'a Url) {
+      self.blocked.remove(node);
+      for waiting in self.waiting.remove(node).unwrap_or_default() {
+        if self.blocked.contains(waiting) {
+          self.unblock(waiting);
+        }
+      }
+    }
+  }
+
+  let mut cycles = BTreeSet::new();
+  for start in edges.keys() {
+    let mut search = Search {
+      edges,
+      start,
+      stack: Vec::new(),
+      blocked: BTreeSet::new(),
+      waiting: BTreeMap::new(),
+      cycles,
+    };
+    search.visit(start);
+    cycles = search.cycles;
+  }
+
+  cycles.into_iter().collect()
 }
 
 #[cfg(test)]
 mod tests {
+  use super::{bundle, DuplicatePolicy, SchemaMap};
+  use crate::dialect::Dialect;
+  use crate::error::BundleError;
+  use serde_json::json;
+
   // use super::SchemaId;
   // use crate::logging;
   // use log::debug;
@@ -183,4 +1187,308 @@ mod tests {
   // logging::init_logging(true);
   // debug!("This ia a debug entry.");
   // }
+
+  fn registry_of(schemas: &[&serde_json::Value]) -> SchemaMap {
+    let mut registry = SchemaMap::new();
+    for schema in schemas {
+      registry.register_schema((*schema).clone()).unwrap();
+    }
+    registry
+  }
+
+  #[test]
+  fn test_duplicate_policy_applies_to_whole_documents() {
+    let first = json!({"$id": "https://p.com/b.json", "$defs": {}});
+    let second = json!({
+      "$id": "https://p.com/b.json",
+      "$defs": {"m": {"$id": "m.json", "type": "string"}}
+    });
+    let root = json!({"$id": "https://p.com/root.json", "$ref": "m.json"});
+
+    let mut registry = SchemaMap::new();
+    registry.duplicate_policy = DuplicatePolicy::FirstWins;
+    for schema in [&first, &second, &root] {
+      registry.register_schema(schema.clone()).unwrap();
+    }
+    assert!(registry.get("https://p.com/m.json").is_none());
+    let bundled = bundle(&root, &registry).unwrap();
+    assert!(matches!(
+      bundled.unresolved.as_slice(),
+      [BundleError::UnresolvedRef { .. }]
+    ));
+
+    let mut registry = SchemaMap::new();
+    registry.duplicate_policy = DuplicatePolicy::LastWins;
+    for schema in [&second, &first, &root] {
+      registry.register_schema(schema.clone()).unwrap();
+    }
+    assert!(registry.get("https://p.com/m.json").is_none());
+    assert!(registry.get_item("m.json").is_none());
+
+    registry.register_schema(second.clone()).unwrap();
+    let bundled = bundle(&root, &registry).unwrap();
+    assert!(bundled.unresolved.is_empty());
+    assert_eq!(bundled.schema["$defs"]["https://p.com/b.json"], second);
+  }
+
+  #[test]
+  fn test_self_referencing_schema() {
+    let node = json!({
+      "$id": "https://foo.com/node.json",
+      "properties": {"children": {"items": {"$ref": "node.json"}}}
+    });
+    let root = json!({"$id": "https://foo.com/root.json", "$ref": "node.json"});
+
+    let bundled = bundle(&root, &registry_of(&[&root, &node])).unwrap();
+
+    assert_eq!(bundled.schema["$defs"]["https://foo.com/node.json"], node);
+    assert_eq!(bundled.cycles.len(), 1);
+    assert_eq!(bundled.cycles[0].len(), 1);
+    assert_eq!(bundled.cycles[0][0].as_str(), "https://foo.com/node.json");
+  }
+
+  #[test]
+  fn test_mutually_recursive_schemas() {
+    let a = json!({"$id": "https://foo.com/a.json", "properties": {"b": {"$ref": "b.json"}}});
+    let b =
+      json!({"$id": "https://foo.com/b.json", "properties": {"a": {"$ref": "a.json#/properties"}}});
+
+    let bundled = bundle(&a, &registry_of(&[&a, &b])).unwrap();
+
+    assert_eq!(bundled.schema["$defs"].as_object().unwrap().len(), 1);
+    assert_eq!(bundled.schema["$defs"]["https://foo.com/b.json"], b);
+    let cycle: Vec<&str> = bundled.cycles[0].iter().map(|u| u.as_str()).collect();
+    assert_eq!(
+      cycle,
+      vec!["https://foo.com/a.json", "https://foo.com/b.json"]
+    );
+
+    // a → b → c → a and a → c → a share an edge, and both are listed.
+    let a = json!({
+      "$id": "https://foo.com/a.json",
+      "properties": {"b": {"$ref": "b.json"}, "c": {"$ref": "c.json"}}
+    });
+    let b = json!({"$id": "https://foo.com/b.json", "items": {"$ref": "c.json"}});
+    let c = json!({"$id": "https://foo.com/c.json", "not": {"$ref": "a.json"}});
+
+    let bundled = bundle(&a, &registry_of(&[&a, &b, &c])).unwrap();
+
+    let cycles: Vec<Vec<&str>> = bundled
+      .cycles
+      .iter()
+      .map(|cycle| cycle.iter().map(|u| u.as_str()).collect())
+      .collect();
+    assert_eq!(
+      cycles,
+      vec![
+        vec![
+          "https://foo.com/a.json",
+          "https://foo.com/b.json",
+          "https://foo.com/c.json"
+        ],
+        vec!["https://foo.com/a.json", "https://foo.com/c.json"],
+      ]
+    );
+  }
+
+  #[test]
+  fn test_internal_pointer_is_not_a_cycle() {
+    let root = json!({
+      "$id": "https://foo.com/root.json",
+      "$defs": {"name": {"type": "string"}},
+      "properties": {"name": {"$ref": "#/$defs/name"}}
+    });
+
+    let bundled = bundle(&root, &registry_of(&[&root])).unwrap();
+
+    assert_eq!(bundled.schema, root);
+    assert!(bundled.cycles.is_empty());
+  }
+
+  #[test]
+  fn test_unresolved_and_broken_refs_are_reported() {
+    let root = json!({
+      "$id": "https://foo.com/root.json",
+      "properties": {
+        "a": {"$ref": "missing.json"},
+        "b": {"$ref": "#/$defs/missing"}
+      }
+    });
+
+    let bundled = bundle(&root, &registry_of(&[&root])).unwrap();
+
+    assert_eq!(bundled.schema, root);
+    assert_eq!(bundled.unresolved.len(), 2);
+    assert!(matches!(
+      &bundled.unresolved[0],
+      BundleError::UnresolvedRef { target, .. } if target == "https://foo.com/missing.json"
+    ));
+    assert!(matches!(
+      &bundled.unresolved[1],
+      BundleError::BrokenRef { location, .. } if location == "/properties/b"
+    ));
+  }
+
+  #[test]
+  fn test_properties_named_like_data_keywords_are_schemas() {
+    let money = json!({"$id": "https://foo.com/money.json", "type": "number"});
+    let root = json!({
+      "$id": "https://foo.com/root.json",
+      "properties": {
+        "default": {"$ref": "money.json"},
+        "enum": {"$ref": "other.json"},
+        "const": {"$id": "const.json", "$anchor": "fixed"}
+      },
+      "default": {"$ref": "ignored.json"}
+    });
+    let registry = registry_of(&[&money, &root]);
+
+    let bundled = bundle(&root, &registry).unwrap();
+    assert_eq!(bundled.embedded, ["https://foo.com/money.json"]);
+    assert_eq!(bundled.unresolved.len(), 1);
+    assert!(matches!(
+      &bundled.unresolved[0],
+      BundleError::UnresolvedRef { target, .. } if target == "https://foo.com/other.json"
+    ));
+
+    let embedded = registry.get_item("https://foo.com/const.json").unwrap();
+    assert_eq!(embedded.location.as_deref(), Some("/properties/const"));
+    assert_eq!(embedded.anchors.plain["fixed"], "");
+  }
+
+  #[test]
+  fn test_ref_to_resource_embedded_in_another_document() {
+    let partner = json!({
+      "$id": "https://partner.com/bundle.json",
+      "$defs": {"money": {"$id": "money.json", "type": "number"}}
+    });
+    let root =
+      json!({"$id": "https://foo.com/root.json", "$ref": "https://partner.com/money.json"});
+
+    let bundled = bundle(&root, &registry_of(&[&root, &partner])).unwrap();
+
+    let defs = bundled.schema["$defs"].as_object().unwrap();
+    assert_eq!(defs.len(), 1);
+    assert_eq!(defs["https://partner.com/bundle.json"], partner);
+  }
+
+  #[test]
+  fn test_draft04_documents_follow_their_own_rules() {
+    // No `$schema`, so this only reads as draft-04 with the default changed.
+    let legacy = json!({
+      "id": "https://foo.com/legacy.json",
+      "definitions": {
+        "code": {"id": "#code", "type": "string"},
+        "ignored": {"$ref": "#code", "properties": {"x": {"$ref": "missing.json"}}}
+      }
+    });
+    let root = json!({
+      "$schema": "https://json-schema.org/draft/2020-12/schema",
+      "$id": "https://foo.com/root.json",
+      "$ref": "legacy.json#code"
+    });
+
+    let mut registry = SchemaMap::new();
+    registry.default_dialect = Dialect::Draft04;
+    registry.register_schema(legacy.clone()).unwrap();
+    registry.register_schema(root.clone()).unwrap();
+
+    let item = registry.get_item("https://foo.com/legacy.json").unwrap();
+    assert_eq!(item.dialect, Dialect::Draft04);
+    assert_eq!(item.anchors.plain["code"], "/definitions/code");
+
+    let bundled = bundle(&root, &registry).unwrap();
+    assert!(bundled.unresolved.is_empty(), "{:?}", bundled.unresolved);
+    let embedded = &bundled.schema["$defs"]["https://foo.com/legacy.json"];
+    assert_eq!(embedded["$schema"], Dialect::Draft04.schema_uri());
+    assert_eq!(embedded["id"], legacy["id"]);
+  }
+
+  #[test]
+  fn test_tree_shaking_leaves_out_unreachable_definitions() {
+    let library = json!({
+      "$id": "https://foo.com/common.json",
+      "$defs": {
+        "money": {"$ref": "#/$defs/amount"},
+        "amount": {"type": "number"},
+        "address": {"$ref": "geo.json"}
+      }
+    });
+    let geo = json!({"$id": "https://foo.com/geo.json"});
+    let root = json!({
+      "$id": "https://foo.com/order.json",
+      "properties": {"total": {"$ref": "common.json#/$defs/money"}},
+      "$defs": {"local": {"type": "string"}}
+    });
+    let mut registry = registry_of(&[&library, &geo, &root]);
+
+    let bundled = bundle(&root, &registry).unwrap();
+    assert_eq!(bundled.embedded, ["https://foo.com/common.json"]);
+    let defs = bundled.schema["$defs"].as_object().unwrap();
+    assert_eq!(
+      defs.keys().collect::<Vec<_>>(),
+      ["https://foo.com/common.json", "local"]
+    );
+    let common = defs["https://foo.com/common.json"]["$defs"]
+      .as_object()
+      .unwrap();
+    assert_eq!(common.keys().collect::<Vec<_>>(), ["amount", "money"]);
+
+    registry.tree_shake = false;
+    let bundled = bundle(&root, &registry).unwrap();
+    assert_eq!(bundled.embedded.len(), 2);
+    assert_eq!(
+      bundled.schema["$defs"]["https://foo.com/common.json"],
+      library
+    );
+  }
+
+  #[test]
+  fn test_tree_shaking_ignores_refs_in_unreachable_definitions() {
+    let library = json!({
+      "$id": "https://foo.com/common.json",
+      "$defs": {
+        "amount": {"type": "number"},
+        "missing": {"$ref": "gone.json"},
+        "loop": {"$ref": "common.json#/$defs/loop"}
+      }
+    });
+    let root = json!({
+      "$id": "https://foo.com/order.json",
+      "properties": {"total": {"$ref": "common.json#/$defs/amount"}}
+    });
+    let registry = registry_of(&[&library, &root]);
+
+    let bundled = bundle(&root, &registry).unwrap();
+    assert!(bundled.unresolved.is_empty());
+    assert!(bundled.cycles.is_empty());
+  }
+
+  #[test]
+  fn test_tree_shaking_keeps_dynamic_anchors_in_embedded_resources() {
+    let list = json!({
+      "$schema": "https://json-schema.org/draft/2020-12/schema",
+      "$id": "https://foo.com/list.json",
+      "type": "array",
+      "items": {"$dynamicRef": "#item"},
+      "$defs": {"item": {"$dynamicAnchor": "item"}}
+    });
+    let strlist = json!({
+      "$schema": "https://json-schema.org/draft/2020-12/schema",
+      "$id": "https://foo.com/strlist.json",
+      "$ref": "list.json",
+      "$defs": {"item": {"$dynamicAnchor": "item", "type": "string"}}
+    });
+    let root = json!({
+      "$schema": "https://json-schema.org/draft/2020-12/schema",
+      "$id": "https://foo.com/root.json",
+      "$ref": "strlist.json"
+    });
+    let registry = registry_of(&[&list, &strlist, &root]);
+
+    let bundled = bundle(&root, &registry).unwrap();
+    let defs = &bundled.schema["$defs"];
+    assert_eq!(defs["https://foo.com/strlist.json"], strlist);
+    assert_eq!(defs["https://foo.com/list.json"], list);
+  }
 }
@@ -0,0 +1,304 @@
+use log::debug;
+use serde_json::{json, Value as JsonValue};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use url::Url;
+
+use crate::error::BundleError;
+use crate::inputs::{parse_document, InputDocument};
+use crate::output::write_atomically;
+use crate::retriever::{HttpRetriever, SchemaRetriever};
+
+/// The lockfile used when none is given.
+pub const DEFAULT_LOCKFILE: &str = "bundle-schema.lock";
+/// The cache directory used when none is given.
+pub const DEFAULT_CACHE_DIR: &str = ".bundle-schema-cache";
+
+const LOCKFILE_VERSION: u64 = 1;
+
+/// Whether [`CachingRetriever`] may use the network, and update the lockfile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CacheMode {
+  /// Fetch schemas missing from the cache, and lock any that aren't locked yet.
+  #[default]
+  Online,
+  /// Never fetch; every remote schema must already be cached. Schemas that aren't
+  /// locked yet are still locked.
+  Offline,
+  /// Never fetch, and never change the lockfile; every remote schema must already be
+  /// cached and locked.
+  Frozen,
+}
+
+/// The hash recorded in the lockfile for a schema's contents.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::cache::content_hash;
+/// assert_eq!(
+///   content_hash(""),
+///   "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+/// );
+/// ```
+pub fn content_hash(contents: &str) -> String {
+  let digest = Sha256::digest(contents.as_bytes());
+  let hex: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+  format!("sha256:{hex}")
+}
+
+/// The content hash of every fetched schema, keyed by URI.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Lockfile {
+  pub schemas: BTreeMap<String, String>,
+}
+
+impl Lockfile {
+  /// Read the lockfile at `path`, or an empty one if it doesn't exist.
+  pub fn load(path: &Path) -> Result<Self, BundleError> {
+    let source = path.display().to_string();
+    let contents = match fs::read_to_string(path) {
+      Ok(contents) => contents,
+      Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Lockfile::default()),
+      Err(e) => {
+        return Err(BundleError::Io {
+          path: source,
+          source: e,
+        })
+      }
+    };
+
+    let value: JsonValue =
+      serde_json::from_str(&contents).map_err(|e| BundleError::from_json_error(&source, &e))?;
+    Self::from_json_value(&value).map_err(|message| BundleError::InvalidLockfile {
+      path: source,
+      message,
+    })
+  }
+
+  /// Read a lockfile out of its JSON form.
+  ///
+  /// # Examples
+  ///
+  /// ```rust
+  /// # use bundle_schema::cache::Lockfile;
+  /// let lockfile = Lockfile::from_json_value(&serde_json::json!({
+  ///   "version": 1,
+  ///   "schemas": {"https://schemas.example.com/money.json": "sha256:abcd"}
+  /// }))
+  /// .unwrap();
+  /// assert_eq!(lockfile.schemas["https://schemas.example.com/money.json"], "sha256:abcd");
+  ///
+  /// assert!(Lockfile::from_json_value(&serde_json::json!({"version": 2, "schemas": {}})).is_err());
+  /// ```
+  pub fn from_json_value(value: &JsonValue) -> Result<Self, String> {
+    match value.get("version").and_then(JsonValue::as_u64) {
+      Some(LOCKFILE_VERSION) => {}
+      Some(v) => return Err(format!("unsupported version {v}")),
+      None => return Err(String::from("missing `version`")),
+    }
+    let Some(entries) = value.get("schemas").and_then(JsonValue::as_object) else {
+      return Err(String::from("`schemas` should be an object"));
+    };
+
+    let mut schemas = BTreeMap::new();
+    for (uri, hash) in entries {
+      let Some(hash) = hash.as_str() else {
+        return Err(format!("the hash for «{uri}» should be a string"));
+      };
+      schemas.insert(uri.clone(), hash.to_owned());
+    }
+    Ok(Lockfile { schemas })
+  }
+
+  pub fn to_json_value(&self) -> JsonValue {
+    json!({
+      "version": LOCKFILE_VERSION,
+      "schemas": self.schemas,
+    })
+  }
+
+  /// Write the lockfile to `path`, replacing it atomically.
+  pub fn save(&self, path: &Path) -> io::Result<()> {
+    let mut contents = serde_json::to_string_pretty(&self.to_json_value())?;
+    contents.push('\n');
+    write_atomically(path, contents.as_bytes())
+  }
+}
+
+/// Fetches `http://` and `https://` schemas through an on-disk cache, checking each
+/// one against the content hash recorded in a lockfile so that bundles are
+/// reproducible.
+///
+/// A schema whose contents don't match the lockfile is an error; delete its entry from
+/// the lockfile (and the cache) to accept the new contents.
+#[derive(Debug)]
+pub struct CachingRetriever {
+  http: HttpRetriever,
+  cache_dir: PathBuf,
+  lockfile_path: PathBuf,
+  lockfile: RefCell<Lockfile>,
+  mode: CacheMode,
+}
+
+impl CachingRetriever {
+  /// Cache schemas in `cache_dir`, and lock them in the lockfile at `lockfile_path`,
+  /// which is read straight away.
+  pub fn new(
+    http: HttpRetriever,
+    cache_dir: &Path,
+    lockfile_path: &Path,
+    mode: CacheMode,
+  ) -> Result<Self, BundleError> {
+    Ok(CachingRetriever {
+      http,
+      cache_dir: cache_dir.to_owned(),
+      lockfile_path: lockfile_path.to_owned(),
+      lockfile: RefCell::new(Lockfile::load(lockfile_path)?),
+      mode,
+    })
+  }
+
+  /// Where the contents of `uri` are cached: a file named after the hash of the URI.
+  pub fn cache_path(&self, uri: &Url) -> PathBuf {
+    let key = content_hash(uri.as_str());
+    let key = key.trim_start_matches("sha256:");
+    self.cache_dir.join(format!("{key}.schema"))
+  }
+
+  fn read_cached(&self, uri: &Url) -> Result<Option<String>, BundleError> {
+    let path = self.cache_path(uri);
+    match fs::read_to_string(&path) {
+      Ok(contents) => {
+        debug!("Using the cached copy of «{uri}» in «{}»", path.display());
+        Ok(Some(contents))
+      }
+      Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(BundleError::Io {
+        path: path.display().to_string(),
+        source: e,
+      }),
+    }
+  }
+
+  fn write_cached(&self, uri: &Url, contents: &str) -> Result<(), BundleError> {
+    let path = self.cache_path(uri);
+    fs::create_dir_all(&self.cache_dir)
+      .and_then(|_| write_atomically(&path, contents.as_bytes()))
+      .map_err(|e| BundleError::Io {
+        path: path.display().to_string(),
+        source: e,
+      })
+  }
+
+  /// Check `contents` against the lockfile, locking them if `uri` isn't locked yet.
+  fn check_lock(&self, uri: &Url, contents: &str) -> Result<(), BundleError> {
+    let actual = content_hash(contents);
+    let mut lockfile = self.lockfile.borrow_mut();
+
+    match lockfile.schemas.get(uri.as_str()) {
+      Some(locked) if *locked == actual => Ok(()),
+      Some(locked) => Err(BundleError::HashMismatch {
+        uri: uri.to_string(),
+        locked: locked.clone(),
+        actual,
+      }),
+      None if self.mode == CacheMode::Frozen => Err(BundleError::NotLocked(uri.to_string())),
+      None => {
+        lockfile.schemas.insert(uri.to_string(), actual);
+        lockfile
+          .save(&self.lockfile_path)
+          .map_err(|e| BundleError::Io {
+            path: self.lockfile_path.display().to_string(),
+            source: e,
+          })
+      }
+    }
+  }
+}
+
+impl SchemaRetriever for CachingRetriever {
+  fn retrieve(&self, uri: &Url) -> Result<Option<InputDocument>, BundleError> {
+    if !matches!(uri.scheme(), "http" | "https") {
+      return Ok(None);
+    }
+
+    let contents = match self.read_cached(uri)? {
+      Some(contents) => {
+        self.check_lock(uri, &contents)?;
+        contents
+      }
+      None if self.mode != CacheMode::Online => {
+        return Err(BundleError::NotCached(uri.to_string()))
+      }
+      None => {
+        let Some(contents) = self.http.fetch(uri)? else {
+          return Ok(None);
+        };
+        self.check_lock(uri, &contents)?;
+        self.write_cached(uri, &contents)?;
+        contents
+      }
+    };
+
+    parse_document(uri.as_str(), &contents, None).map(Some)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::scratch_dir;
+
+  fn retriever(dir: &Path, mode: CacheMode) -> CachingRetriever {
+    CachingRetriever::new(
+      HttpRetriever::new(),
+      &dir.join("cache"),
+      &dir.join(DEFAULT_LOCKFILE),
+      mode,
+    )
+    .unwrap()
+  }
+
+  #[test]
+  fn test_offline_modes_use_the_cache_and_lockfile() {
+    let dir = scratch_dir("cache-offline");
+    let uri = Url::parse("https://schemas.example.com/money.json").unwrap();
+    let contents = r#"{"type": "number"}"#;
+
+    // Nothing is cached yet, and nothing may be fetched.
+    let err = retriever(&dir, CacheMode::Offline)
+      .retrieve(&uri)
+      .unwrap_err();
+    assert!(matches!(err, BundleError::NotCached(_)), "{err}");
+
+    let cache = retriever(&dir, CacheMode::Offline);
+    cache.write_cached(&uri, contents).unwrap();
+
+    // Frozen refuses to lock anything new.
+    let err = retriever(&dir, CacheMode::Frozen)
+      .retrieve(&uri)
+      .unwrap_err();
+    assert!(matches!(err, BundleError::NotLocked(_)), "{err}");
+
+    // Offline locks it.
+    let doc = cache.retrieve(&uri).unwrap().unwrap();
+    assert_eq!(doc.schema["type"], "number");
+    let lockfile = Lockfile::load(&dir.join(DEFAULT_LOCKFILE)).unwrap();
+    assert_eq!(lockfile.schemas[uri.as_str()], content_hash(contents));
+
+    // After which frozen is happy, until the cached copy changes.
+    assert!(retriever(&dir, CacheMode::Frozen).retrieve(&uri).is_ok());
+    cache.write_cached(&uri, r#"{"type": "string"}"#).unwrap();
+    let err = retriever(&dir, CacheMode::Frozen)
+      .retrieve(&uri)
+      .unwrap_err();
+    assert!(matches!(err, BundleError::HashMismatch { .. }), "{err}");
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -0,0 +1,548 @@
+use log::{debug, warn};
+use serde_json::{Map, Value as JsonValue};
+use std::collections::{BTreeMap, BTreeSet};
+use url::Url;
+
+use crate::bundler::{normalize_uri, resolve_in_resource, SchemaId, SchemaMap, SchemaMapItem};
+use crate::dialect::Dialect;
+use crate::error::BundleError;
+use crate::migrate::{restructure_registered, Migration};
+use crate::pointer::{decode_fragment, encode_fragment, escape_token};
+use crate::resolver::{
+  base_identifier, find_anchors, resolve_reference, Anchors, DATA_KEYWORDS,
+  NAMED_SUBSCHEMA_KEYWORDS,
+};
+
+/// Keywords that only identify or hold resources for references to use, so they're
+/// dropped once every reference has been inlined.
+const REFERENCE_ONLY_KEYWORDS: [&str; 7] = [
+  "$anchor",
+  "$defs",
+  "$dynamicAnchor",
+  "$id",
+  "$recursiveAnchor",
+  "$schema",
+  "definitions",
+];
+
+/// What to do about a reference that leads back into a schema it's already being
+/// inlined into, which would otherwise be inlined forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum RecursionPolicy {
+  /// Fail, reporting the cycle.
+  #[default]
+  Fail,
+  /// Keep a `$ref` to the place the schema was first inlined.
+  KeepRefs,
+}
+
+/// A schema with its references inlined.
+#[derive(Debug)]
+pub struct Dereferenced {
+  pub schema: JsonValue,
+  /// JSON Pointers to the `$ref`s kept to break recursion.
+  pub kept_refs: Vec<String>,
+  /// References that were left as-is because they couldn't be resolved.
+  pub unresolved: Vec<BundleError>,
+}
+
+/// A schema being inlined, on the way from the root to the node being walked.
+struct Inlining<'a> {
+  node: &'a JsonValue,
+  /// The absolute reference it was reached through.
+  uri: String,
+  /// JSON Pointer to its inlined copy in the output.
+  location: String,
+}
+
+struct Dereferencer<'a> {
+  schemas: &'a SchemaMap,
+  root: &'a JsonValue,
+  root_id: Option<Url>,
+  root_anchors: Anchors,
+  root_dialect: Dialect,
+  /// With a 2020-12 root, each registered document holding resources written in an
+  /// older dialect, migrated to 2020-12.
+  migrations: &'a BTreeMap<Url, Migration>,
+  /// Documents whose migration problems, or dialect, have been warned about.
+  warned: BTreeSet<Url>,
+  policy: RecursionPolicy,
+  stack: Vec<Inlining<'a>>,
+  kept_refs: Vec<String>,
+  unresolved: Vec<BundleError>,
+}
+
+/// Replace every resolvable `$ref` and `$dynamicRef` beneath `root` with a copy of
+/// its target, found in `root` itself or among `schemas`.
+///
+/// A reference with sibling keywords becomes an `allOf` holding the target alongside
+/// them, so both still apply, except up to draft-07, where the siblings are ignored
+/// and so dropped. `$dynamicRef` and `$recursiveRef` are treated like `$ref`,
+/// ignoring the dynamic scope. Keywords that only serve references, like `$defs`,
+/// `$anchor` and any `$id` besides the root's, are dropped from the result. References
+/// that can't be resolved are left in place, made absolute.
+///
+/// Under a 2020-12 root, resources written in an older dialect are
+/// [`migrate`](crate::migrate::migrate)d before they're inlined, so that draft-04's
+/// boolean `exclusiveMinimum` or an array `items` keep their meaning. Under any other
+/// root, inlining a resource written in a different dialect is warned about.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::bundler::SchemaMap;
+/// # use bundle_schema::dereference::{dereference, RecursionPolicy};
+/// let mut registry = SchemaMap::new();
+/// registry
+///   .register_schema(serde_json::json!({"$id": "https://foo.com/money.json", "type": "number"}))
+///   .unwrap();
+///
+/// let root = serde_json::json!({
+///   "$id": "https://foo.com/order.json",
+///   "properties": {
+///     "total": {"$ref": "money.json"},
+///     "tax": {"$ref": "money.json", "minimum": 0},
+///     "id": {"$ref": "#/$defs/id"}
+///   },
+///   "$defs": {"id": {"type": "string"}}
+/// });
+///
+/// let dereferenced = dereference(&root, &registry, RecursionPolicy::Fail).unwrap();
+/// assert_eq!(
+///   dereferenced.schema,
+///   serde_json::json!({
+///     "$id": "https://foo.com/order.json",
+///     "properties": {
+///       "total": {"type": "number"},
+///       "tax": {"minimum": 0, "allOf": [{"type": "number"}]},
+///       "id": {"type": "string"}
+///     }
+///   })
+/// );
+/// ```
+pub fn dereference(
+  root: &JsonValue,
+  schemas: &SchemaMap,
+  policy: RecursionPolicy,
+) -> Result<Dereferenced, BundleError> {
+  let root_dialect = Dialect::detect(root, schemas.default_dialect);
+  let root_id = SchemaId::from_json_value_as(root, root_dialect)
+    .ok()
+    .map(|id| id.full_id);
+  let migrations = match root_dialect {
+    Dialect::Draft2020_12 => restructure_registered(schemas),
+    _ => BTreeMap::new(),
+  };
+  let mut dereferencer = Dereferencer {
+    schemas,
+    root,
+    root_id: root_id.clone(),
+    root_anchors: find_anchors(root, root_dialect),
+    root_dialect,
+    migrations: &migrations,
+    warned: BTreeSet::new(),
+    policy,
+    stack: vec![Inlining {
+      node: root,
+      uri: root_id
+        .as_ref()
+        .map_or_else(|| String::from("#"), Url::to_string),
+      location: String::new(),
+    }],
+    kept_refs: Vec::new(),
+    unresolved: Vec::new(),
+  };
+
+  let mut schema = dereferencer.schema(root, root_id.as_ref(), root_dialect, String::new())?;
+
+  // The root keeps its identity, so that the refs kept to break recursion resolve against it.
+  if let (Some(obj), Some(root_obj)) = (schema.as_object_mut(), root.as_object()) {
+    for keyword in [root_dialect.id_keyword(), "$schema"] {
+      if let Some(value) = root_obj.get(keyword) {
+        obj.insert(keyword.to_owned(), value.clone());
+      }
+    }
+  }
+
+  Ok(Dereferenced {
+    schema,
+    kept_refs: dereferencer.kept_refs,
+    unresolved: dereferencer.unresolved,
+  })
+}
+
+impl<'a> Dereferencer<'a> {
+  /// Find the node a reference points at, along with the base URI and dialect of
+  /// the resource holding it.
+  fn lookup(
+    &mut self,
+    reference: &str,
+    target: &Url,
+    location: &str,
+  ) -> Option<(&'a JsonValue, Url, Dialect)> {
+    let fragment = target.fragment().unwrap_or_default();
+    let mut resource_uri = target.clone();
+    resource_uri.set_fragment(None);
+
+    let schemas = self.schemas;
+    let found = match &self.root_id {
+      Some(id) if *id == resource_uri => {
+        resolve_in_resource(self.root, &self.root_anchors, fragment).map(|n| (n, self.root_dialect))
+      }
+      _ => match schemas.get_item(&resource_uri) {
+        Some(item) => match self.migrated(item, fragment) {
+          Some((node, fragment)) => {
+            let anchors = find_anchors(node, Dialect::Draft2020_12);
+            resolve_in_resource(node, &anchors, &fragment).map(|n| (n, Dialect::Draft2020_12))
+          }
+          None => {
+            if item.dialect != self.root_dialect && self.warned.insert(resource_uri.clone()) {
+              warn!(
+                "Inlining «{resource_uri}», written in {:?}, into a {:?} schema as it is",
+                item.dialect, self.root_dialect
+              );
+            }
+            item.resolve_fragment(fragment).map(|n| (n, item.dialect))
+          }
+        },
+        None => {
+          self.unresolved.push(BundleError::UnresolvedRef {
+            reference: reference.to_owned(),
+            location: location.to_owned(),
+            target: target.to_string(),
+          });
+          return None;
+        }
+      },
+    };
+
+    match found {
+      Ok((found, dialect)) => Some((found, resource_uri, dialect)),
+      Err(e) => {
+        self.unresolved.push(BundleError::BrokenRef {
+          reference: reference.to_owned(),
+          location: location.to_owned(),
+          source: Box::new(e),
+        });
+        None
+      }
+    }
+  }
+
+  /// The migrated copy of a resource written before 2020-12, with `fragment` moved to
+  /// follow it, if there is one.
+  fn migrated(&mut self, item: &SchemaMapItem, fragment: &str) -> Option<(&'a JsonValue, String)> {
+    if item.dialect == Dialect::Draft2020_12 {
+      return None;
+    }
+    let document = item.parent.as_ref().unwrap_or(&item.id.full_id);
+    let migration = self.migrations.get(document)?;
+    let location = item.location.as_deref().unwrap_or_default();
+    let moved = migration.relocate(location);
+    let node = migration.schema.pointer(&moved)?;
+
+    if self.warned.insert(document.clone()) {
+      for problem in &migration.problems {
+        warn!("Migrating «{document}» to inline it {problem}");
+      }
+    }
+    let fragment = match decode_fragment(fragment) {
+      Ok(pointer) if pointer.starts_with('/') => {
+        let target = migration.relocate(&format!("{location}{pointer}"));
+        encode_fragment(target.strip_prefix(&moved).unwrap_or(&pointer))
+      }
+      _ => fragment.to_owned(),
+    };
+    Some((node, fragment))
+  }
+
+  /// Inline the target of a reference, whose copy will end up at `inlined_at`. Returns
+  /// `None` if the reference can't be resolved.
+  fn inline(
+    &mut self,
+    reference: &str,
+    base: Option<&Url>,
+    location: &str,
+    inlined_at: String,
+  ) -> Result<Option<JsonValue>, BundleError> {
+    let target = match resolve_reference(base, reference) {
+      Ok(target) => target,
+      Err(e) => {
+        debug!("Unable to resolve «{reference}» at «{location}»: {e}");
+        self.unresolved.push(BundleError::UnresolvedRef {
+          reference: reference.to_owned(),
+          location: location.to_owned(),
+          target: reference.to_owned(),
+        });
+        return Ok(None);
+      }
+    };
+    let Some((node, resource_uri, dialect)) = self.lookup(reference, &target, location) else {
+      return Ok(Some(JsonValue::from(Map::from_iter([(
+        String::from("$ref"),
+        JsonValue::String(target.into()),
+      )]))));
+    };
+
+    if let Some(start) = self.stack.iter().position(|s| std::ptr::eq(s.node, node)) {
+      match self.policy {
+        RecursionPolicy::Fail => {
+          let mut cycle: Vec<String> = self.stack[start..].iter().map(|s| s.uri.clone()).collect();
+          cycle.push(normalize_uri(&target).to_string());
+          return Err(BundleError::RecursiveRef(cycle));
+        }
+        RecursionPolicy::KeepRefs => {
+          let kept = format!("#{}", encode_fragment(&self.stack[start].location));
+          debug!("Keeping a $ref to «{kept}» at «{location}» to break recursion");
+          self.kept_refs.push(location.to_owned());
+          return Ok(Some(JsonValue::from(Map::from_iter([(
+            String::from("$ref"),
+            JsonValue::String(kept),
+          )]))));
+        }
+      }
+    }
+
+    self.stack.push(Inlining {
+      node,
+      uri: normalize_uri(&target).to_string(),
+      location: inlined_at.clone(),
+    });
+    let inlined = self.schema(node, Some(&resource_uri), dialect, inlined_at);
+    self.stack.pop();
+
+    inlined.map(Some)
+  }
+
+  /// Dereference a subschema found at `location` in the output.
+  fn schema(
+    &mut self,
+    node: &'a JsonValue,
+    base: Option<&Url>,
+    dialect: Dialect,
+    location: String,
+  ) -> Result<JsonValue, BundleError> {
+    let JsonValue::Object(map) = node else {
+      return Ok(node.clone());
+    };
+
+    let dialect = dialect.within(node);
+    let own_base = match base_identifier(map, dialect) {
+      Some(id) => resolve_reference(base, id).ok(),
+      None => base.cloned(),
+    };
+    let only_ref =
+      dialect.ignores_ref_siblings() && map.get("$ref").is_some_and(JsonValue::is_string);
+
+    let mut out = Map::new();
+    let mut references = Vec::new();
+    for (key, value) in map {
+      let child_location = format!("{location}/{}", escape_token(key));
+      let is_ref = key == "$ref" || Some(key.as_str()) == dialect.dynamic_ref_keyword();
+      match key.as_str() {
+        _ if is_ref && value.is_string() => references.push(value.as_str().unwrap_or_default()),
+        _ if only_ref => {}
+        k if k == dialect.id_keyword() && value.is_string() => {}
+        k if REFERENCE_ONLY_KEYWORDS.contains(&k) => {}
+        k if DATA_KEYWORDS.contains(&k) => {
+          out.insert(key.clone(), value.clone());
+        }
+        k if NAMED_SUBSCHEMA_KEYWORDS.contains(&k) && value.is_object() => {
+          let mut named = Map::new();
+          for (name, subschema) in value.as_object().into_iter().flatten() {
+            let subschema_location = format!("{child_location}/{}", escape_token(name));
+            named.insert(
+              name.clone(),
+              self.schema(subschema, own_base.as_ref(), dialect, subschema_location)?,
+            );
+          }
+          out.insert(key.clone(), JsonValue::Object(named));
+        }
+        _ => {
+          out.insert(
+            key.clone(),
+            self.value(value, own_base.as_ref(), dialect, child_location)?,
+          );
+        }
+      }
+    }
+
+    if references.is_empty() {
+      return Ok(JsonValue::Object(out));
+    }
+    // A lone reference is replaced by its target; one with siblings joins them in an `allOf`.
+    if references.len() == 1 && out.is_empty() {
+      let reference = references[0];
+      return Ok(
+        self
+          .inline(reference, own_base.as_ref(), &location, location.clone())?
+          .unwrap_or_else(|| node.clone()),
+      );
+    }
+
+    let mut all_of = match out.remove("allOf") {
+      Some(JsonValue::Array(all_of)) => all_of,
+      Some(other) => vec![other],
+      None => Vec::new(),
+    };
+    for reference in references {
+      let inlined_at = format!("{location}/allOf/{}", all_of.len());
+      match self.inline(reference, own_base.as_ref(), &location, inlined_at)? {
+        Some(inlined) => all_of.push(inlined),
+        None => {
+          out.insert(
+            String::from("$ref"),
+            JsonValue::String(reference.to_owned()),
+          );
+        }
+      }
+    }
+    out.insert(String::from("allOf"), JsonValue::Array(all_of));
+
+    Ok(JsonValue::Object(out))
+  }
+
+  /// Dereference a keyword's value, which may be a subschema or an array of them.
+  fn value(
+    &mut self,
+    value: &'a JsonValue,
+    base: Option<&Url>,
+    dialect: Dialect,
+    location: String,
+  ) -> Result<JsonValue, BundleError> {
+    match value {
+      JsonValue::Object(_) => self.schema(value, base, dialect, location),
+      JsonValue::Array(items) => items
+        .iter()
+        .enumerate()
+        .map(|(idx, item)| self.value(item, base, dialect, format!("{location}/{idx}")))
+        .collect::<Result<Vec<_>, _>>()
+        .map(JsonValue::Array),
+      _ => Ok(value.clone()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn tree() -> JsonValue {
+    json!({
+      "$id": "https://foo.com/tree.json",
+      "type": "object",
+      "properties": {
+        "value": {"type": "number"},
+        "children": {"type": "array", "items": {"$ref": "#"}}
+      }
+    })
+  }
+
+  #[test]
+  fn test_recursion_fails_with_the_cycle() {
+    let err = dereference(&tree(), &SchemaMap::new(), RecursionPolicy::Fail).unwrap_err();
+    let BundleError::RecursiveRef(cycle) = err else {
+      panic!("unexpected error {err}");
+    };
+    assert_eq!(
+      cycle,
+      ["https://foo.com/tree.json", "https://foo.com/tree.json"]
+    );
+  }
+
+  #[test]
+  fn test_older_resources_are_migrated_before_inlining() {
+    let mut registry = SchemaMap::new();
+    registry
+      .register_schema(json!({
+        "$schema": "http://json-schema.org/draft-04/schema#",
+        "id": "https://foo.com/old.json",
+        "definitions": {
+          "count": {"minimum": 1, "exclusiveMinimum": true},
+          "pair": {
+            "items": [{"type": "string"}, {"$ref": "#/definitions/count"}],
+            "additionalItems": false
+          }
+        }
+      }))
+      .unwrap();
+    let root = json!({
+      "$id": "https://foo.com/root.json",
+      "properties": {
+        "count": {"$ref": "old.json#/definitions/count"},
+        "pair": {"$ref": "old.json#/definitions/pair"},
+        "second": {"$ref": "old.json#/definitions/pair/items/1"}
+      }
+    });
+
+    let dereferenced = dereference(&root, &registry, RecursionPolicy::Fail).unwrap();
+    assert_eq!(
+      dereferenced.schema,
+      json!({
+        "$id": "https://foo.com/root.json",
+        "properties": {
+          "count": {"exclusiveMinimum": 1},
+          "pair": {
+            "prefixItems": [{"type": "string"}, {"exclusiveMinimum": 1}],
+            "items": false
+          },
+          "second": {"exclusiveMinimum": 1}
+        }
+      })
+    );
+    assert!(dereferenced.unresolved.is_empty());
+  }
+
+  #[test]
+  fn test_recursion_keeps_internal_refs() {
+    let mut registry = SchemaMap::new();
+    registry
+      .register_schema(json!({
+        "$id": "https://foo.com/node.json",
+        "properties": {"next": {"$ref": "#"}, "tree": {"$ref": "tree.json"}}
+      }))
+      .unwrap();
+    let root = json!({
+      "$id": "https://foo.com/root.json",
+      "properties": {"head": {"$ref": "node.json", "description": "The first node"}}
+    });
+    registry.register_schema(tree()).unwrap();
+
+    let dereferenced = dereference(&root, &registry, RecursionPolicy::KeepRefs).unwrap();
+    assert_eq!(
+      dereferenced.schema,
+      json!({
+        "$id": "https://foo.com/root.json",
+        "properties": {
+          "head": {
+            "description": "The first node",
+            "allOf": [{
+              "properties": {
+                "next": {"$ref": "#/properties/head/allOf/0"},
+                "tree": {
+                  "type": "object",
+                  "properties": {
+                    "value": {"type": "number"},
+                    "children": {
+                      "type": "array",
+                      "items": {"$ref": "#/properties/head/allOf/0/properties/tree"}
+                    }
+                  }
+                }
+              }
+            }]
+          }
+        }
+      })
+    );
+    assert_eq!(
+      dereferenced.kept_refs,
+      [
+        "/properties/head/allOf/0/properties/next",
+        "/properties/head/allOf/0/properties/tree/properties/children/items"
+      ]
+    );
+    assert!(dereferenced.unresolved.is_empty());
+  }
+}
@@ -0,0 +1,655 @@
+use log::warn;
+use percent_encoding::percent_decode_str;
+use serde_json::{Map, Value as JsonValue};
+use std::collections::{BTreeSet, HashMap};
+use url::Url;
+
+use crate::bundler::SchemaId;
+use crate::migrate::numeric_exclusive_bounds;
+use crate::pointer::{decode_fragment, encode_fragment, escape_token, resolve_pointer};
+use crate::resolver::{
+  base_identifier, find_anchors, find_embedded_resources, resolve_reference, Anchors,
+  DATA_KEYWORDS, NAMED_SUBSCHEMA_KEYWORDS,
+};
+
+/// The `$schema` of a draft-07 document.
+pub const DRAFT_07_URI: &str = "http://json-schema.org/draft-07/schema#";
+
+/// Keywords added by 2019-09 and 2020-12 that mean nothing to a draft-07 validator.
+const POST_DRAFT_07_KEYWORDS: [&str; 8] = [
+  "contentSchema",
+  "dependentRequired",
+  "dependentSchemas",
+  "maxContains",
+  "minContains",
+  "prefixItems",
+  "unevaluatedItems",
+  "unevaluatedProperties",
+];
+
+/// A JSON Schema dialect, which decides the keywords that identify schemas, name
+/// anchors and make references.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, clap::ValueEnum)]
+pub enum Dialect {
+  /// Identifies schemas with `id`; `$ref` siblings are ignored.
+  #[value(name = "draft-04")]
+  Draft04,
+  /// Identifies schemas with `$id`; `$ref` siblings are ignored.
+  #[value(name = "draft-06")]
+  Draft06,
+  /// Like draft-06.
+  #[value(name = "draft-07")]
+  Draft07,
+  /// Adds `$anchor` and `$recursiveRef`; `$ref` siblings apply.
+  #[value(name = "2019-09")]
+  Draft2019_09,
+  /// Replaces `$recursiveRef` with `$dynamicRef`.
+  #[default]
+  #[value(name = "2020-12")]
+  Draft2020_12,
+}
+
+impl Dialect {
+  /// The dialect a `$schema` URI names, if it's one of the known ones.
+  ///
+  /// # Examples
+  ///
+  /// ```rust
+  /// # use bundle_schema::dialect::Dialect;
+  /// assert_eq!(Dialect::from_schema_uri("http://json-schema.org/draft-04/schema#"), Some(Dialect::Draft04));
+  /// assert_eq!(Dialect::from_schema_uri("https://json-schema.org/draft-07/schema"), Some(Dialect::Draft07));
+  /// assert_eq!(
+  ///   Dialect::from_schema_uri("https://json-schema.org/draft/2019-09/schema"),
+  ///   Some(Dialect::Draft2019_09)
+  /// );
+  /// assert_eq!(Dialect::from_schema_uri("https://example.com/my-meta-schema"), None);
+  /// ```
+  pub fn from_schema_uri(uri: &str) -> Option<Self> {
+    let uri = uri.trim_end_matches('#');
+    let uri = uri
+      .strip_prefix("https://")
+      .or_else(|| uri.strip_prefix("http://"))
+      .unwrap_or(uri);
+
+    match uri {
+      "json-schema.org/draft-04/schema" => Some(Dialect::Draft04),
+      "json-schema.org/draft-06/schema" => Some(Dialect::Draft06),
+      "json-schema.org/draft-07/schema" => Some(Dialect::Draft07),
+      "json-schema.org/draft/2019-09/schema" => Some(Dialect::Draft2019_09),
+      "json-schema.org/draft/2020-12/schema" => Some(Dialect::Draft2020_12),
+      _ => None,
+    }
+  }
+
+  /// The dialect of a schema, from its `$schema`, falling back to `default` when it
+  /// has none or it names an unknown dialect. An unknown `$schema` is warned about
+  /// once, when the schema is registered, not here.
+  ///
+  /// # Examples
+  ///
+  /// ```rust
+  /// # use bundle_schema::dialect::Dialect;
+  /// let schema = serde_json::json!({"$schema": "http://json-schema.org/draft-04/schema#"});
+  /// assert_eq!(Dialect::detect(&schema, Dialect::Draft2020_12), Dialect::Draft04);
+  /// assert_eq!(Dialect::detect(&serde_json::json!({}), Dialect::Draft07), Dialect::Draft07);
+  /// ```
+  pub fn detect(schema: &JsonValue, default: Dialect) -> Self {
+    schema
+      .get("$schema")
+      .and_then(JsonValue::as_str)
+      .and_then(Self::from_schema_uri)
+      .unwrap_or(default)
+  }
+
+  /// The meta-schema URI naming this dialect in `$schema`.
+  pub fn schema_uri(self) -> &'static str {
+    match self {
+      Dialect::Draft04 => "http://json-schema.org/draft-04/schema#",
+      Dialect::Draft06 => "http://json-schema.org/draft-06/schema#",
+      Dialect::Draft07 => DRAFT_07_URI,
+      Dialect::Draft2019_09 => "https://json-schema.org/draft/2019-09/schema",
+      Dialect::Draft2020_12 => "https://json-schema.org/draft/2020-12/schema",
+    }
+  }
+
+  /// The keyword holding a schema's identifier.
+  pub fn id_keyword(self) -> &'static str {
+    match self {
+      Dialect::Draft04 => "id",
+      _ => "$id",
+    }
+  }
+
+  /// Whether keywords beside a `$ref` are ignored, including the identifier.
+  pub fn ignores_ref_siblings(self) -> bool {
+    self <= Dialect::Draft07
+  }
+
+  /// Whether an identifier that's only a fragment, like `#foo`, names an anchor.
+  pub fn has_fragment_ids(self) -> bool {
+    self <= Dialect::Draft07
+  }
+
+  /// The keyword for references resolved against the dynamic scope, if any.
+  pub fn dynamic_ref_keyword(self) -> Option<&'static str> {
+    match self {
+      Dialect::Draft2019_09 => Some("$recursiveRef"),
+      Dialect::Draft2020_12 => Some("$dynamicRef"),
+      _ => None,
+    }
+  }
+
+  /// The dialect of `node`, if it names a known one with `$schema`, else `self`.
+  pub(crate) fn within(self, node: &JsonValue) -> Self {
+    node
+      .get("$schema")
+      .and_then(JsonValue::as_str)
+      .and_then(Self::from_schema_uri)
+      .unwrap_or(self)
+  }
+}
+
+/// The JSON Schema dialect to lay bundles out for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum OutputDialect {
+  /// Embedded resources keep their `$id`, under `$defs` keyed by that `$id`.
+  #[default]
+  #[value(name = "2020-12")]
+  Draft2020_12,
+  /// Embedded resources lose their `$id`, under `definitions` keyed by a short name,
+  /// and every reference to them is rewritten to a JSON Pointer.
+  #[value(name = "draft-07")]
+  Draft07,
+}
+
+/// Where a schema resource ends up in a draft-07 bundle.
+struct Placement {
+  /// JSON Pointer to the resource from the root.
+  location: String,
+  anchors: Anchors,
+}
+
+/// Rewrite a 2020-12 bundle into a draft-07 one.
+///
+/// Each resource in `embedded`, a `$defs` key of the bundle's root, moves under
+/// `definitions`, named after the last segment of its path. References to it, or
+/// anything inside it, become JSON Pointers from the root, since draft-07 can't find
+/// resources by `$id` inside a document. `$id`s besides the root's are dropped, as
+/// they'd change what those pointers are resolved against, along with anchors and
+/// `$dynamicRef`s, which draft-07 doesn't have. A `$ref` with sibling keywords moves
+/// into an `allOf`, since draft-07 would ignore its siblings. Keywords draft-07 doesn't
+/// have, like `prefixItems` and `unevaluatedProperties`, are left as they are with a
+/// warning, since a draft-07 validator will ignore them.
+///
+/// Each resource's own dialect decides how it's read, with `default_dialect` for
+/// those that don't name one with `$schema`. A draft-04 root's `id` becomes `$id`, and
+/// draft-04's boolean `exclusiveMinimum` and `exclusiveMaximum` take the value of
+/// `minimum` and `maximum`, as draft-06 has them.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::dialect::{to_draft07, Dialect};
+/// let bundled = serde_json::json!({
+///   "$id": "https://foo.com/order.json",
+///   "properties": {
+///     "total": {"$ref": "types/money.json#/$defs/amount", "description": "The total"}
+///   },
+///   "$defs": {
+///     "https://foo.com/types/money.json": {
+///       "$id": "https://foo.com/types/money.json",
+///       "$defs": {"amount": {"type": "number"}}
+///     }
+///   }
+/// });
+///
+/// let embedded = [String::from("https://foo.com/types/money.json")];
+/// let downgraded = to_draft07(&bundled, &embedded, Dialect::Draft2020_12);
+/// assert_eq!(
+///   downgraded,
+///   serde_json::json!({
+///     "$schema": "http://json-schema.org/draft-07/schema#",
+///     "$id": "https://foo.com/order.json",
+///     "properties": {
+///       "total": {
+///         "description": "The total",
+///         "allOf": [{"$ref": "#/definitions/money/$defs/amount"}]
+///       }
+///     },
+///     "definitions": {
+///       "money": {"$defs": {"amount": {"type": "number"}}}
+///     }
+///   })
+/// );
+/// ```
+pub fn to_draft07(bundled: &JsonValue, embedded: &[String], default_dialect: Dialect) -> JsonValue {
+  let root_dialect = Dialect::detect(bundled, default_dialect);
+  let root_id = SchemaId::from_json_value_as(bundled, root_dialect)
+    .ok()
+    .map(|id| id.full_id);
+
+  let mut taken: BTreeSet<String> = bundled
+    .get("definitions")
+    .and_then(JsonValue::as_object)
+    .map(|defs| defs.keys().cloned().collect())
+    .unwrap_or_default();
+  let mut names = Vec::new();
+  for key in embedded {
+    let name = unique_name(definition_name(key), &mut taken);
+    names.push((key, name));
+  }
+
+  let mut placements = HashMap::new();
+  if let Some(root_id) = &root_id {
+    let moved: Vec<String> = embedded
+      .iter()
+      .map(|key| format!("/$defs/{}", escape_token(key)))
+      .collect();
+    place(
+      bundled,
+      root_id,
+      root_dialect,
+      String::new(),
+      &moved,
+      &mut placements,
+    );
+  }
+  for (key, name) in &names {
+    let (Ok(id), Some(node)) = (Url::parse(key), bundled["$defs"].get(key.as_str())) else {
+      continue;
+    };
+    place(
+      node,
+      &id,
+      root_dialect.within(node),
+      format!("/definitions/{}", escape_token(name)),
+      &[],
+      &mut placements,
+    );
+  }
+
+  let mut schema = bundled.clone();
+  rewrite(
+    &mut schema,
+    root_id.as_ref(),
+    root_dialect,
+    &placements,
+    String::new(),
+  );
+
+  let Some(root) = schema.as_object_mut() else {
+    return schema;
+  };
+  let mut moved = Map::new();
+  if let Some(JsonValue::Object(defs)) = root.get_mut("$defs") {
+    for (key, name) in names {
+      if let Some(resource) = defs.remove(key) {
+        moved.insert(name, resource);
+      }
+    }
+    if defs.is_empty() {
+      root.remove("$defs");
+    }
+  }
+  if !moved.is_empty() {
+    let definitions = root
+      .entry("definitions")
+      .or_insert_with(|| JsonValue::Object(Map::new()));
+    if let Some(definitions) = definitions.as_object_mut() {
+      definitions.extend(moved);
+    }
+  }
+  root.insert(
+    String::from("$schema"),
+    JsonValue::String(DRAFT_07_URI.to_owned()),
+  );
+
+  schema
+}
+
+/// Record where a resource, and every resource nested inside it, ends up. Nested
+/// resources under any of the `skip` locations are placed separately.
+fn place(
+  node: &JsonValue,
+  id: &Url,
+  dialect: Dialect,
+  location: String,
+  skip: &[String],
+  placements: &mut HashMap<Url, Placement>,
+) {
+  for nested in find_embedded_resources(node, id, dialect) {
+    let skipped = skip.iter().any(|s| {
+      nested
+        .location
+        .strip_prefix(s.as_str())
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    });
+    if skipped {
+      continue;
+    }
+    if let Ok(nested_node) = resolve_pointer(node, &nested.location) {
+      placements.insert(
+        nested.id,
+        Placement {
+          location: format!("{location}{}", nested.location),
+          anchors: find_anchors(nested_node, nested.dialect),
+        },
+      );
+    }
+  }
+  placements.insert(
+    id.clone(),
+    Placement {
+      location,
+      anchors: find_anchors(node, dialect),
+    },
+  );
+}
+
+/// A short name for an embedded resource: the last segment of its path, up to the
+/// first `.`.
+fn definition_name(id: &str) -> String {
+  let Ok(url) = Url::parse(id) else {
+    return String::from("schema");
+  };
+  let segment = url
+    .path_segments()
+    .and_then(|segments| segments.rev().find(|s| !s.is_empty()))
+    .map(|s| percent_decode_str(s).decode_utf8_lossy().into_owned())
+    .unwrap_or_default();
+
+  match segment.split('.').next() {
+    Some(stem) if !stem.is_empty() => stem.to_owned(),
+    _ => url.host_str().unwrap_or("schema").to_owned(),
+  }
+}
+
+fn unique_name(name: String, taken: &mut BTreeSet<String>) -> String {
+  let mut candidate = name.clone();
+  let mut n = 2;
+  while taken.contains(&candidate) {
+    candidate = format!("{name}_{n}");
+    n += 1;
+  }
+  taken.insert(candidate.clone());
+  candidate
+}
+
+/// Rewrite a reference to point at where its target ends up, falling back to its
+/// absolute URI.
+fn rewrite_ref(
+  reference: &str,
+  base: Option<&Url>,
+  placements: &HashMap<Url, Placement>,
+) -> String {
+  let Ok(target) = resolve_reference(base, reference) else {
+    return reference.to_owned();
+  };
+  let mut resource = target.clone();
+  resource.set_fragment(None);
+  let Some(placement) = placements.get(&resource) else {
+    return target.into();
+  };
+
+  let fragment = target.fragment().unwrap_or_default();
+  let pointer = match decode_fragment(fragment) {
+    Ok(pointer) if pointer.is_empty() || pointer.starts_with('/') => Some(pointer),
+    Ok(anchor) => placement.anchors.plain.get(&anchor).cloned(),
+    Err(_) => None,
+  };
+  match pointer {
+    Some(pointer) => format!(
+      "#{}",
+      encode_fragment(&format!("{}{pointer}", placement.location))
+    ),
+    None => target.into(),
+  }
+}
+
+fn rewrite(
+  node: &mut JsonValue,
+  base: Option<&Url>,
+  dialect: Dialect,
+  placements: &HashMap<Url, Placement>,
+  location: String,
+) {
+  let top = location.is_empty();
+  match node {
+    JsonValue::Object(_) => {
+      let dialect = dialect.within(node);
+      let JsonValue::Object(map) = node else {
+        return;
+      };
+      let own_base = match base_identifier(map, dialect) {
+        Some(id) => resolve_reference(base, id).ok(),
+        None => base.cloned(),
+      };
+      let id_keyword = dialect.id_keyword();
+      if map.get(id_keyword).is_some_and(JsonValue::is_string) {
+        let id = map.remove(id_keyword);
+        if let (true, Some(id)) = (top, id) {
+          map.insert(String::from("$id"), id);
+        }
+      }
+      if !top {
+        map.remove("$schema");
+      }
+      for keyword in ["$anchor", "$dynamicAnchor", "$recursiveAnchor"] {
+        map.remove(keyword);
+      }
+
+      if let Some(keyword) = dialect.dynamic_ref_keyword() {
+        if let Some(dynamic) = map.remove(keyword) {
+          warn!("Draft-07 has no `{keyword}`; replacing «{dynamic}» with a `$ref`");
+          map.entry("$ref").or_insert(dynamic);
+        }
+      }
+      if let Some(JsonValue::String(reference)) = map.get("$ref") {
+        let rewritten = rewrite_ref(reference, own_base.as_ref(), placements);
+        map.insert(String::from("$ref"), JsonValue::String(rewritten));
+      }
+
+      // Keywords beside a `$ref` that were already being ignored stay that way.
+      if dialect.ignores_ref_siblings() && map.contains_key("$ref") {
+        return;
+      }
+      if dialect == Dialect::Draft04 {
+        for message in numeric_exclusive_bounds(map) {
+          warn!("Draft-07 has no boolean exclusive bounds at «{location}»: {message}");
+        }
+      }
+      let unsupported = unsupported_keywords(map, dialect);
+      if !unsupported.is_empty() {
+        warn!(
+          "Draft-07 has no {} at «{location}»; leaving them for validators to ignore",
+          unsupported.join(", ")
+        );
+      }
+      for (key, value) in map.iter_mut() {
+        if key == "$ref" || DATA_KEYWORDS.contains(&key.as_str()) {
+          continue;
+        }
+        let child = format!("{location}/{}", escape_token(key));
+        match value {
+          JsonValue::Object(named) if NAMED_SUBSCHEMA_KEYWORDS.contains(&key.as_str()) => {
+            for (name, subschema) in named.iter_mut() {
+              let location = format!("{child}/{}", escape_token(name));
+              rewrite(subschema, own_base.as_ref(), dialect, placements, location);
+            }
+          }
+          _ => rewrite(value, own_base.as_ref(), dialect, placements, child),
+        }
+      }
+
+      // Draft-07 ignores every keyword beside a `$ref`.
+      if map.len() > 1 {
+        if let Some(reference) = map.remove("$ref") {
+          let wrapped = JsonValue::Object(Map::from_iter([(String::from("$ref"), reference)]));
+          match map.get_mut("allOf") {
+            Some(JsonValue::Array(all_of)) => all_of.push(wrapped),
+            _ => {
+              map.insert(String::from("allOf"), JsonValue::Array(vec![wrapped]));
+            }
+          }
+        }
+      }
+    }
+    JsonValue::Array(items) => {
+      for (idx, item) in items.iter_mut().enumerate() {
+        rewrite(item, base, dialect, placements, format!("{location}/{idx}"));
+      }
+    }
+    _ => {}
+  }
+}
+
+/// The keywords of a subschema, written in `dialect`, that draft-07 doesn't have,
+/// quoted. An `items` beside `prefixItems` counts, since it only applies to the items
+/// after those.
+fn unsupported_keywords(map: &Map<String, JsonValue>, dialect: Dialect) -> Vec<String> {
+  if dialect < Dialect::Draft2019_09 {
+    return Vec::new();
+  }
+  let mut unsupported: Vec<String> = map
+    .keys()
+    .filter(|key| POST_DRAFT_07_KEYWORDS.contains(&key.as_str()))
+    .map(|key| format!("`{key}`"))
+    .collect();
+  if map.contains_key("prefixItems") && map.contains_key("items") {
+    unsupported.push(String::from("2020-12's `items`"));
+  }
+  unsupported
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_draft07_rewrites_anchors_nested_resources_and_clashing_names() {
+    let bundled = json!({
+      "$id": "https://foo.com/order.json",
+      "$schema": "https://json-schema.org/draft/2020-12/schema",
+      "properties": {
+        "a": {"$ref": "v1/money.json#currency"},
+        "b": {"$ref": "v2/money.json"},
+        "c": {"$ref": "https://foo.com/v2/inner.json"},
+        "d": {"$ref": "#/definitions/money"},
+        "e": {"$ref": "https://elsewhere.com/x.json"}
+      },
+      "definitions": {"money": {"type": "integer"}},
+      "$defs": {
+        "https://foo.com/v1/money.json": {
+          "$id": "https://foo.com/v1/money.json",
+          "$schema": "https://json-schema.org/draft/2020-12/schema",
+          "$defs": {"c": {"$anchor": "currency", "type": "string"}}
+        },
+        "https://foo.com/v2/money.json": {
+          "$id": "https://foo.com/v2/money.json",
+          "$defs": {"inner": {"$id": "inner.json", "$ref": "money.json"}}
+        }
+      }
+    });
+    let embedded = [
+      String::from("https://foo.com/v1/money.json"),
+      String::from("https://foo.com/v2/money.json"),
+    ];
+
+    assert_eq!(
+      to_draft07(&bundled, &embedded, Dialect::Draft2020_12),
+      json!({
+        "$id": "https://foo.com/order.json",
+        "$schema": DRAFT_07_URI,
+        "properties": {
+          "a": {"$ref": "#/definitions/money_2/$defs/c"},
+          "b": {"$ref": "#/definitions/money_3"},
+          "c": {"$ref": "#/definitions/money_3/$defs/inner"},
+          "d": {"$ref": "#/definitions/money"},
+          "e": {"$ref": "https://elsewhere.com/x.json"}
+        },
+        "definitions": {
+          "money": {"type": "integer"},
+          "money_2": {"$defs": {"c": {"type": "string"}}},
+          "money_3": {"$defs": {"inner": {"$ref": "#/definitions/money_3"}}}
+        }
+      })
+    );
+  }
+
+  #[test]
+  fn test_draft07_reports_keywords_it_lacks() {
+    let schema = json!({
+      "prefixItems": [true],
+      "items": false,
+      "unevaluatedProperties": false,
+      "minimum": 0
+    });
+    let JsonValue::Object(map) = &schema else {
+      unreachable!();
+    };
+
+    assert_eq!(
+      unsupported_keywords(map, Dialect::Draft2020_12),
+      [
+        "`prefixItems`",
+        "`unevaluatedProperties`",
+        "2020-12's `items`"
+      ]
+    );
+    assert!(unsupported_keywords(map, Dialect::Draft07).is_empty());
+  }
+
+  #[test]
+  fn test_draft07_converts_draft04_exclusive_bounds() {
+    let bundled = json!({
+      "$schema": "http://json-schema.org/draft-04/schema#",
+      "id": "https://foo.com/order.json",
+      "properties": {
+        "total": {"$ref": "money.json"},
+        "count": {"minimum": 0, "exclusiveMinimum": false, "maximum": 9}
+      },
+      "$defs": {
+        "https://foo.com/money.json": {
+          "$schema": "http://json-schema.org/draft-04/schema#",
+          "id": "https://foo.com/money.json",
+          "minimum": 1,
+          "exclusiveMinimum": true,
+          "exclusiveMaximum": true
+        }
+      }
+    });
+    let embedded = [String::from("https://foo.com/money.json")];
+
+    assert_eq!(
+      to_draft07(&bundled, &embedded, Dialect::Draft2020_12),
+      json!({
+        "$schema": DRAFT_07_URI,
+        "$id": "https://foo.com/order.json",
+        "properties": {
+          "total": {"$ref": "#/definitions/money"},
+          "count": {"minimum": 0, "maximum": 9}
+        },
+        "definitions": {
+          "money": {"exclusiveMinimum": 1, "exclusiveMaximum": true}
+        }
+      })
+    );
+  }
+
+  #[test]
+  fn test_draft07_rewrites_properties_named_like_data_keywords() {
+    let bundled = json!({
+      "$id": "https://foo.com/order.json",
+      "properties": {"default": {"$ref": "money.json"}},
+      "$defs": {"https://foo.com/money.json": {"$id": "https://foo.com/money.json"}}
+    });
+    let embedded = [String::from("https://foo.com/money.json")];
+
+    let downgraded = to_draft07(&bundled, &embedded, Dialect::Draft2020_12);
+    assert_eq!(
+      downgraded["properties"]["default"]["$ref"],
+      "#/definitions/money"
+    );
+  }
+}
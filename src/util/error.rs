@@ -0,0 +1,231 @@
+use std::fmt;
+use std::io;
+
+use crate::pointer::PointerError;
+
+/// Everything that can go wrong while reading, registering and bundling schemas.
+#[derive(Debug)]
+pub enum BundleError {
+  /// The schema has no `$id`.
+  MissingId,
+  /// The schema's `$id` isn't a string. Holds the offending value, serialized.
+  NonStringId(String),
+  /// The schema's `$id` isn't a valid absolute URL.
+  InvalidId { id: String, source: url::ParseError },
+  /// An input couldn't be read.
+  Io { path: String, source: io::Error },
+  /// A schema couldn't be fetched over the network.
+  Fetch { uri: String, message: String },
+  /// A remote schema isn't in the cache, and may not be fetched.
+  NotCached(String),
+  /// A remote schema's contents don't match the hash recorded in the lockfile.
+  HashMismatch {
+    uri: String,
+    locked: String,
+    actual: String,
+  },
+  /// A remote schema isn't in the lockfile, and the lockfile may not be changed.
+  NotLocked(String),
+  /// The lockfile is valid JSON, but not a valid lockfile.
+  InvalidLockfile { path: String, message: String },
+  /// An input or `--exclude` glob pattern is malformed.
+  InvalidPattern { pattern: String, message: String },
+  /// An input isn't valid JSON.
+  JsonParse {
+    path: String,
+    line: usize,
+    column: usize,
+    message: String,
+  },
+  /// An input isn't valid YAML, or uses YAML features that have no JSON equivalent.
+  YamlParse {
+    path: String,
+    line: usize,
+    column: usize,
+    message: String,
+  },
+  /// An input isn't valid JSON5, or holds numbers JSON can't represent.
+  Json5Parse {
+    path: String,
+    line: usize,
+    column: usize,
+    message: String,
+  },
+  /// A reference points at a resource that isn't registered.
+  UnresolvedRef {
+    /// The reference exactly as it was written.
+    reference: String,
+    /// JSON Pointer to the object holding the reference.
+    location: String,
+    /// The absolute URI the reference resolved to.
+    target: String,
+  },
+  /// A reference's fragment doesn't exist in the resource it points into.
+  BrokenRef {
+    reference: String,
+    location: String,
+    source: Box<PointerError>,
+  },
+  /// A reference leads back into a schema it's being inlined into. Holds the
+  /// references making up the cycle, ending with the one that closes it.
+  RecursiveRef(Vec<String>),
+  /// Two different schemas declared the same `$id`.
+  DuplicateId {
+    id: String,
+    /// Where the schema registered first came from.
+    first: Option<String>,
+    /// Where the conflicting schema came from.
+    second: Option<String>,
+  },
+  /// Nothing registered matches the requested root schema.
+  RootNotFound(String),
+  /// More than one registered schema matches the requested root schema.
+  AmbiguousRoot {
+    root: String,
+    candidates: Vec<String>,
+  },
+  /// The root schema can't hold embedded resources, because it (or its `$defs`) isn't an object.
+  UnembeddableRoot(String),
+}
+
+impl BundleError {
+  /// Build a [`BundleError::JsonParse`] out of a `serde_json` error.
+  ///
+  /// # Examples
+  ///
+  /// ```rust
+  /// # use bundle_schema::error::BundleError;
+  /// let e = serde_json::from_str::<serde_json::Value>("{\n  \"a\": }").unwrap_err();
+  /// let err = BundleError::from_json_error("a.json", &e);
+  /// assert_eq!(err.to_string(), "failed to parse «a.json» at line 2, column 8: expected value");
+  /// ```
+  pub fn from_json_error(path: &str, e: &serde_json::Error) -> Self {
+    let (line, column) = (e.line(), e.column());
+    let message = e.to_string();
+    let message = message
+      .strip_suffix(&format!(" at line {line} column {column}"))
+      .unwrap_or(&message)
+      .to_owned();
+
+    BundleError::JsonParse {
+      path: path.to_owned(),
+      line,
+      column,
+      message,
+    }
+  }
+}
+
+impl fmt::Display for BundleError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      BundleError::MissingId => write!(f, "schema has no `$id` property"),
+      BundleError::NonStringId(v) => write!(f, "`$id` value «{v}» is not a string"),
+      BundleError::InvalidId { id, source } => {
+        write!(f, "unable to parse `$id` value «{id}» as a URL: {source}")
+      }
+      BundleError::Io { path, source } => write!(f, "failed to read «{path}»: {source}"),
+      BundleError::Fetch { uri, message } => write!(f, "failed to fetch «{uri}»: {message}"),
+      BundleError::NotCached(uri) => {
+        write!(f, "«{uri}» isn't cached, and fetching is disabled")
+      }
+      BundleError::HashMismatch {
+        uri,
+        locked,
+        actual,
+      } => write!(
+        f,
+        "the contents of «{uri}» have changed: the lockfile has {locked}, but it's now {actual}"
+      ),
+      BundleError::NotLocked(uri) => {
+        write!(
+          f,
+          "«{uri}» isn't in the lockfile, and the lockfile is frozen"
+        )
+      }
+      BundleError::InvalidLockfile { path, message } => {
+        write!(f, "invalid lockfile «{path}»: {message}")
+      }
+      BundleError::InvalidPattern { pattern, message } => {
+        write!(f, "invalid glob pattern «{pattern}»: {message}")
+      }
+      BundleError::JsonParse {
+        path,
+        line,
+        column,
+        message,
+      } => write!(
+        f,
+        "failed to parse «{path}» at line {line}, column {column}: {message}"
+      ),
+      BundleError::YamlParse {
+        path,
+        line,
+        column,
+        message,
+      } => write!(
+        f,
+        "failed to parse YAML «{path}» at line {line}, column {column}: {message}"
+      ),
+      BundleError::Json5Parse {
+        path,
+        line,
+        column,
+        message,
+      } => write!(
+        f,
+        "failed to parse JSON5 «{path}» at line {line}, column {column}: {message}"
+      ),
+      BundleError::UnresolvedRef {
+        reference,
+        location,
+        target,
+      } => write!(
+        f,
+        "no registered schema for reference «{reference}» at «{location}» (resolved to «{target}»)"
+      ),
+      BundleError::BrokenRef {
+        reference,
+        location,
+        source,
+      } => write!(
+        f,
+        "broken reference «{reference}» at «{location}»: {source}"
+      ),
+      BundleError::RecursiveRef(cycle) => write!(
+        f,
+        "unable to inline a recursive reference: {}",
+        cycle.join(" -> ")
+      ),
+      BundleError::DuplicateId { id, first, second } => {
+        let unknown = "<unknown source>";
+        write!(
+          f,
+          "$id «{id}» is declared with different contents in «{}» and «{}»",
+          first.as_deref().unwrap_or(unknown),
+          second.as_deref().unwrap_or(unknown)
+        )
+      }
+      BundleError::RootNotFound(root) => {
+        write!(f, "no input file or `$id` matches the root «{root}»")
+      }
+      BundleError::AmbiguousRoot { root, candidates } => write!(
+        f,
+        "the root «{root}» is ambiguous; it could be any of {}",
+        candidates.join(", ")
+      ),
+      BundleError::UnembeddableRoot(why) => write!(f, "unable to embed resources: {why}"),
+    }
+  }
+}
+
+impl std::error::Error for BundleError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      BundleError::InvalidId { source, .. } => Some(source),
+      BundleError::Io { source, .. } => Some(source),
+      BundleError::BrokenRef { source, .. } => Some(source.as_ref()),
+      _ => None,
+    }
+  }
+}
@@ -0,0 +1,302 @@
+use serde::de::{self, Deserialize, Deserializer, EnumAccess, MapAccess, SeqAccess, Visitor};
+use serde_json::{Map, Number, Value as JsonValue};
+use std::fmt;
+
+use crate::error::BundleError;
+
+/// Parse a JSON-with-comments document.
+///
+/// `//` and `/* */` comments are allowed, as are trailing commas in objects and
+/// arrays. They're blanked out before parsing, so error positions still match the
+/// original document.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::formats::from_jsonc_str;
+/// let schema = from_jsonc_str(r#"{
+///   // Prices are in cents.
+///   "type": "integer", /* never negative */
+///   "minimum": 0,
+/// }"#, "price.jsonc");
+/// assert_eq!(schema.unwrap(), serde_json::json!({"type": "integer", "minimum": 0}));
+///
+/// let err = from_jsonc_str("{\n  // comment\n  \"type\": }", "price.jsonc").unwrap_err();
+/// assert_eq!(err.to_string(), "failed to parse «price.jsonc» at line 3, column 11: expected value");
+/// ```
+pub fn from_jsonc_str(contents: &str, path: &str) -> Result<JsonValue, BundleError> {
+  let stripped = strip_trailing_commas(&strip_comments(contents));
+  serde_json::from_str(&stripped).map_err(|e| BundleError::from_json_error(path, &e))
+}
+
+/// Replace comments with spaces, keeping line breaks so positions don't move.
+fn strip_comments(contents: &str) -> String {
+  let mut stripped = String::with_capacity(contents.len());
+  let mut chars = contents.chars().peekable();
+  let mut in_string = false;
+
+  while let Some(c) = chars.next() {
+    if in_string {
+      stripped.push(c);
+      match c {
+        '\\' => stripped.extend(chars.next()),
+        '"' => in_string = false,
+        _ => {}
+      }
+      continue;
+    }
+
+    match (c, chars.peek()) {
+      ('"', _) => {
+        in_string = true;
+        stripped.push(c);
+      }
+      ('/', Some('/')) => {
+        while let Some(&next) = chars.peek() {
+          if next == '\n' {
+            break;
+          }
+          chars.next();
+          stripped.push(' ');
+        }
+        stripped.push(' ');
+      }
+      ('/', Some('*')) => {
+        chars.next();
+        stripped.push_str("  ");
+        let mut last = ' ';
+        for next in chars.by_ref() {
+          stripped.push(if next == '\n' { '\n' } else { ' ' });
+          if last == '*' && next == '/' {
+            break;
+          }
+          last = next;
+        }
+      }
+      _ => stripped.push(c),
+    }
+  }
+
+  stripped
+}
+
+/// Replace commas directly followed by a closing `}` or `]` with a space. Comments
+/// must already have been stripped.
+fn strip_trailing_commas(contents: &str) -> String {
+  let chars: Vec<char> = contents.chars().collect();
+  let mut stripped = String::with_capacity(contents.len());
+  let mut in_string = false;
+  let mut escaped = false;
+
+  for (idx, &c) in chars.iter().enumerate() {
+    if in_string {
+      match (escaped, c) {
+        (true, _) => escaped = false,
+        (false, '\\') => escaped = true,
+        (false, '"') => in_string = false,
+        _ => {}
+      }
+    } else if c == '"' {
+      in_string = true;
+    } else if c == ',' {
+      let next = chars[idx + 1..].iter().find(|n| !n.is_whitespace());
+      if matches!(next, Some('}' | ']')) {
+        stripped.push(' ');
+        continue;
+      }
+    }
+    stripped.push(c);
+  }
+
+  stripped
+}
+
+/// Parse a JSON5 document, allowing comments, trailing commas, unquoted keys and
+/// single-quoted strings among other things.
+///
+/// Numbers JSON can't represent, like `NaN` and `Infinity`, are rejected.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::formats::from_json5_str;
+/// let schema = from_json5_str("{type: 'string', maxLength: 0x10, /* hex! */}", "name.json5");
+/// assert_eq!(schema.unwrap(), serde_json::json!({"type": "string", "maxLength": 16}));
+///
+/// let err = from_json5_str("{minimum: NaN}", "bad.json5").unwrap_err();
+/// assert!(err.to_string().contains("NaN"), "{err}");
+/// ```
+pub fn from_json5_str(contents: &str, path: &str) -> Result<JsonValue, BundleError> {
+  json5::from_str::<StrictJson>(contents)
+    .map(|v| v.0)
+    .map_err(|json5::Error::Message { msg, location }| {
+      // Syntax errors come with a multi-line rendering of the offending spot; the
+      // explanation is on the line starting with `=`.
+      let message = msg
+        .lines()
+        .find_map(|l| l.trim_start().strip_prefix("= "))
+        .unwrap_or(&msg)
+        .to_owned();
+
+      BundleError::Json5Parse {
+        path: path.to_owned(),
+        line: location.as_ref().map_or(0, |l| l.line),
+        column: location.as_ref().map_or(0, |l| l.column),
+        message,
+      }
+    })
+}
+
+/// Parse a YAML document into the same `serde_json::Value` a JSON document would give.
+///
+/// YAML that has no JSON equivalent is rejected, with the error pointing at the
+/// offending line: mapping keys that aren't strings, custom tags, non-finite
+/// numbers and aliases that refer back to themselves.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::formats::from_yaml_str;
+/// let schema = from_yaml_str("$id: https://foo.com/a.yaml\ntype: object\nrequired: [name]\n", "a.yaml");
+/// assert_eq!(
+///   schema.unwrap(),
+///   serde_json::json!({"$id": "https://foo.com/a.yaml", "type": "object", "required": ["name"]})
+/// );
+///
+/// let err = from_yaml_str("enum:\n  - a\n  - !custom b\n", "a.yaml").unwrap_err();
+/// assert!(err.to_string().contains("line 3"), "{err}");
+///
+/// let err = from_yaml_str("properties:\n  1: {type: string}\n", "a.yaml").unwrap_err();
+/// assert!(err.to_string().contains("line 2"), "{err}");
+/// ```
+pub fn from_yaml_str(contents: &str, path: &str) -> Result<JsonValue, BundleError> {
+  serde_norway::from_str::<StrictJson>(contents)
+    .map(|v| v.0)
+    .map_err(|e| {
+      let location = e.location();
+      let (line, column) = location.map_or((0, 0), |l| (l.line(), l.column()));
+      let message = e.to_string();
+      let message = message
+        .strip_suffix(&format!(" at line {line} column {column}"))
+        .unwrap_or(&message)
+        .to_owned();
+
+      BundleError::YamlParse {
+        path: path.to_owned(),
+        line,
+        column,
+        message,
+      }
+    })
+}
+
+/// A JSON value built straight from a parser's events, rejecting anything JSON
+/// can't represent. Errors raised this way carry the position they were found at.
+struct StrictJson(JsonValue);
+
+impl<'de> Deserialize<'de> for StrictJson {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    deserializer.deserialize_any(JsonVisitor).map(StrictJson)
+  }
+}
+
+struct JsonVisitor;
+
+impl<'de> Visitor<'de> for JsonVisitor {
+  type Value = JsonValue;
+
+  fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("a value with a JSON equivalent")
+  }
+
+  fn visit_unit<E: de::Error>(self) -> Result<JsonValue, E> {
+    Ok(JsonValue::Null)
+  }
+
+  fn visit_none<E: de::Error>(self) -> Result<JsonValue, E> {
+    Ok(JsonValue::Null)
+  }
+
+  fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<JsonValue, D::Error> {
+    deserializer.deserialize_any(self)
+  }
+
+  fn visit_bool<E: de::Error>(self, v: bool) -> Result<JsonValue, E> {
+    Ok(JsonValue::Bool(v))
+  }
+
+  fn visit_i64<E: de::Error>(self, v: i64) -> Result<JsonValue, E> {
+    Ok(JsonValue::from(v))
+  }
+
+  fn visit_u64<E: de::Error>(self, v: u64) -> Result<JsonValue, E> {
+    Ok(JsonValue::from(v))
+  }
+
+  fn visit_f64<E: de::Error>(self, v: f64) -> Result<JsonValue, E> {
+    Number::from_f64(v)
+      .map(JsonValue::Number)
+      .ok_or_else(|| E::custom(format!("the number {v} has no JSON equivalent")))
+  }
+
+  fn visit_str<E: de::Error>(self, v: &str) -> Result<JsonValue, E> {
+    Ok(JsonValue::String(v.to_owned()))
+  }
+
+  fn visit_string<E: de::Error>(self, v: String) -> Result<JsonValue, E> {
+    Ok(JsonValue::String(v))
+  }
+
+  fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<JsonValue, A::Error> {
+    let mut items = Vec::new();
+    while let Some(StrictJson(item)) = seq.next_element()? {
+      items.push(item);
+    }
+    Ok(JsonValue::Array(items))
+  }
+
+  fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<JsonValue, A::Error> {
+    let mut object = Map::new();
+    while let Some(StringKey(key)) = map.next_key()? {
+      let StrictJson(value) = map.next_value()?;
+      object.insert(key, value);
+    }
+    Ok(JsonValue::Object(object))
+  }
+
+  fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<JsonValue, A::Error> {
+    let (tag, _) = data.variant::<String>()?;
+    Err(de::Error::custom(format!(
+      "the tag «!{tag}» has no JSON equivalent"
+    )))
+  }
+}
+
+/// A mapping key, which JSON only allows to be a string.
+struct StringKey(String);
+
+impl<'de> Deserialize<'de> for StringKey {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    deserializer
+      .deserialize_any(StringKeyVisitor)
+      .map(StringKey)
+  }
+}
+
+struct StringKeyVisitor;
+
+impl<'de> Visitor<'de> for StringKeyVisitor {
+  type Value = String;
+
+  fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("a string mapping key, as JSON doesn't allow any other kind")
+  }
+
+  fn visit_str<E: de::Error>(self, v: &str) -> Result<String, E> {
+    Ok(v.to_owned())
+  }
+
+  fn visit_string<E: de::Error>(self, v: String) -> Result<String, E> {
+    Ok(v)
+  }
+}
@@ -1,31 +1,569 @@
-use log::{debug, error};
-use std::fs::File;
-use std::io::BufReader;
+use glob::{MatchOptions, Pattern};
+use log::{debug, warn};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+use crate::dialect::Dialect;
+use crate::error::BundleError;
+use crate::formats::{from_json5_str, from_jsonc_str, from_yaml_str};
+
+/// A schema document read from one of the inputs.
+#[derive(Debug, Clone)]
+pub struct InputDocument {
+  /// Where the document was read from.
+  pub source: String,
+  pub schema: serde_json::Value,
+  /// The file the document was read from, if it came from one.
+  pub file: Option<InputFile>,
+}
+
+/// A file found while expanding the inputs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputFile {
+  pub path: PathBuf,
+  /// The directory the file was found under: the directory given as an input, the
+  /// fixed leading part of a glob pattern, or the current directory for a file
+  /// given directly.
+  pub root: PathBuf,
+}
+
+/// Characters that would change the meaning of a path segment within a URI.
+const SEGMENT_ESCAPES: &AsciiSet = &CONTROLS.add(b' ').add(b'#').add(b'?').add(b'%');
+
+impl InputFile {
+  /// The file's path relative to its input root, as a relative URI reference.
+  /// Returns `None` when the file isn't within its root.
+  fn relative_reference(&self) -> Option<String> {
+    let root = match self.root.as_os_str().is_empty() {
+      true => Path::new("."),
+      false => &self.root,
+    };
+    let relative = self
+      .path
+      .canonicalize()
+      .ok()?
+      .strip_prefix(root.canonicalize().ok()?)
+      .ok()?
+      .to_path_buf();
+
+    let segments: Vec<String> = relative
+      .components()
+      .map(|c| utf8_percent_encode(&c.as_os_str().to_string_lossy(), SEGMENT_ESCAPES).to_string())
+      .collect();
+    Some(format!("./{}", segments.join("/")))
+  }
+}
+
+/// Work out an `$id` for a document that doesn't declare one.
+///
+/// With a base URI, the id is the base joined with the file's path relative to its
+/// input root, so relative references between files keep working. Without one, or
+/// for a file outside its input root, it's the `file://` URL of the file's absolute
+/// path. A base URI is always treated as a directory, even without a trailing `/`.
+///
+/// Documents that weren't read from a file, like standard input, can't be given an id.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::inputs::{synthesize_id, InputDocument, InputFile};
+/// # use std::path::PathBuf;
+/// # let dir = std::env::temp_dir().join(format!("bundle-schema-synthesize-id-{}", std::process::id()));
+/// # std::fs::create_dir_all(dir.join("types")).unwrap();
+/// # std::fs::write(dir.join("types/money.json"), "{}").unwrap();
+/// let doc = InputDocument {
+///   source: String::from("schemas/types/money.json"),
+///   schema: serde_json::json!({"type": "number"}),
+///   file: Some(InputFile { path: dir.join("types/money.json"), root: dir.clone() }),
+/// };
+///
+/// let base = url::Url::parse("https://schemas.example.com/v1").unwrap();
+/// let id = synthesize_id(&doc, Some(&base)).unwrap();
+/// assert_eq!(id.as_str(), "https://schemas.example.com/v1/types/money.json");
+///
+/// let id = synthesize_id(&doc, None).unwrap();
+/// assert_eq!(id.scheme(), "file");
+/// assert!(id.path().ends_with("/types/money.json"));
+/// # std::fs::remove_dir_all(dir).unwrap();
+/// ```
+pub fn synthesize_id(doc: &InputDocument, base_uri: Option<&Url>) -> Result<Url, BundleError> {
+  let Some(file) = &doc.file else {
+    return Err(BundleError::MissingId);
+  };
+
+  if let Some(base) = base_uri {
+    let mut base = base.clone();
+    if !base.path().ends_with('/') {
+      base.set_path(&format!("{}/", base.path()));
+    }
+
+    match file.relative_reference() {
+      Some(relative) => {
+        return base.join(&relative).map_err(|e| BundleError::InvalidId {
+          id: relative,
+          source: e,
+        })
+      }
+      None => warn!(
+        "«{}» isn't within its input root «{}»; using its file URL as its `$id`.",
+        file.path.display(),
+        file.root.display()
+      ),
+    }
+  }
+
+  let absolute = file
+    .path
+    .canonicalize()
+    .map_err(|e| io_error(&file.path, e))?;
+  Url::from_file_path(&absolute).map_err(|_| BundleError::InvalidId {
+    id: absolute.display().to_string(),
+    source: url::ParseError::RelativeUrlWithoutBase,
+  })
+}
+
+/// Give a document without an `$id` one from [`synthesize_id`], writing it into the
+/// schema so that it's carried through to the bundle. Documents that already have
+/// an `$id`, and schemas that aren't objects, are left alone.
+///
+/// Draft-04 documents use `id` instead, so the document's dialect is worked out from
+/// its `$schema`, falling back to `default_dialect`.
+pub fn ensure_id(
+  doc: &mut InputDocument,
+  base_uri: Option<&Url>,
+  default_dialect: Dialect,
+) -> Result<(), BundleError> {
+  let id_keyword = Dialect::detect(&doc.schema, default_dialect).id_keyword();
+  if !doc.schema.is_object() || doc.schema.get(id_keyword).is_some() {
+    return Ok(());
+  }
+
+  let id = synthesize_id(doc, base_uri)?;
+  debug!("Using synthesized {id_keyword} «{id}» for «{}»", doc.source);
+  if let Some(schema) = doc.schema.as_object_mut() {
+    schema.insert(id_keyword.to_owned(), serde_json::Value::String(id.into()));
+  }
+
+  Ok(())
+}
+
+/// The syntax an input document is written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum InputFormat {
+  /// Strict JSON.
+  Json,
+  /// JSON with `//` and `/* */` comments and trailing commas.
+  Jsonc,
+  /// JSON5.
+  Json5,
+  /// YAML.
+  Yaml,
+}
+
+impl InputFormat {
+  /// Work out a document's format from its file extension, or failing that, by
+  /// sniffing its contents: JSON documents start with `{` or `[`.
+  ///
+  /// # Examples
+  ///
+  /// ```rust
+  /// # use bundle_schema::inputs::InputFormat;
+  /// assert_eq!(InputFormat::detect("a.yml", "{}"), InputFormat::Yaml);
+  /// assert_eq!(InputFormat::detect("a.json", "type: object"), InputFormat::Json);
+  /// assert_eq!(InputFormat::detect("a.jsonc", "{}"), InputFormat::Jsonc);
+  /// assert_eq!(InputFormat::detect("a.json5", "{}"), InputFormat::Json5);
+  /// assert_eq!(InputFormat::detect("a.schema", "  {\"type\": \"object\"}"), InputFormat::Json);
+  /// assert_eq!(InputFormat::detect("a.schema", "type: object"), InputFormat::Yaml);
+  /// ```
+  pub fn detect(path: &str, contents: &str) -> Self {
+    match Path::new(path).extension().and_then(|e| e.to_str()) {
+      Some("json") => InputFormat::Json,
+      Some("jsonc") => InputFormat::Jsonc,
+      Some("json5") => InputFormat::Json5,
+      Some("yaml" | "yml") => InputFormat::Yaml,
+      _ => match contents.trim_start().starts_with(['{', '[']) {
+        true => InputFormat::Json,
+        false => InputFormat::Yaml,
+      },
+    }
+  }
+
+  /// Parse a document written in this format. `source` is only used for errors.
+  pub fn parse(self, source: &str, contents: &str) -> Result<serde_json::Value, BundleError> {
+    match self {
+      InputFormat::Json => {
+        serde_json::from_str(contents).map_err(|e| BundleError::from_json_error(source, &e))
+      }
+      InputFormat::Jsonc => from_jsonc_str(contents, source),
+      InputFormat::Json5 => from_json5_str(contents, source),
+      InputFormat::Yaml => from_yaml_str(contents, source),
+    }
+  }
+}
+
+/// The input naming standard input rather than a file.
+pub const STDIN_INPUT: &str = "-";
+
+/// The source name given to the document read from standard input.
+pub const STDIN_SOURCE: &str = "<stdin>";
+
+/// Parse a document that has already been read. Without an explicit format, it's
+/// detected with [`InputFormat::detect`].
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::inputs::{parse_document, InputFormat};
+/// let doc = parse_document("<generated>", "{\"$id\": \"https://foo.com/a.json\"}", None).unwrap();
+/// assert_eq!(doc.source, "<generated>");
+/// assert_eq!(doc.schema["$id"], "https://foo.com/a.json");
+///
+/// let doc = parse_document("<generated>", "{$id: 'https://foo.com/a.json'}", Some(InputFormat::Json5));
+/// assert_eq!(doc.unwrap().schema["$id"], "https://foo.com/a.json");
+/// ```
+pub fn parse_document(
+  source: &str,
+  contents: &str,
+  format: Option<InputFormat>,
+) -> Result<InputDocument, BundleError> {
+  let format = format.unwrap_or_else(|| InputFormat::detect(source, contents));
+
+  Ok(InputDocument {
+    source: source.to_owned(),
+    schema: format.parse(source, contents)?,
+    file: None,
+  })
+}
+
+fn parse_one_file(
+  input: InputFile,
+  format: Option<InputFormat>,
+) -> Result<InputDocument, BundleError> {
+  let fname = input.path.display().to_string();
+  if fname == STDIN_INPUT {
+    debug!("Parsing standard input");
+    let contents = io::read_to_string(io::stdin()).map_err(|e| BundleError::Io {
+      path: STDIN_SOURCE.to_owned(),
+      source: e,
+    })?;
+    return parse_document(STDIN_SOURCE, &contents, format);
+  }
 
-fn parse_one_file(fname: String) -> Option<serde_json::Value> {
   debug!("Parsing file «{fname}»");
 
-  let fh = match File::open(fname.clone()) {
+  let contents = match fs::read_to_string(&fname) {
     Err(e) => {
-      error!("Failed to parse file «{fname}»: {e:#?}");
-      return None;
+      return Err(BundleError::Io {
+        path: fname,
+        source: e,
+      })
     }
-    Ok(fh) => fh,
+    Ok(contents) => contents,
   };
-  let reader = BufReader::new(fh);
 
-  match serde_json::from_reader(reader) {
-    Err(e) => {
-      error!("Failed to parse «{fname}»: {e:#?}");
-      None
+  Ok(InputDocument {
+    file: Some(input),
+    ..parse_document(&fname, &contents, format)?
+  })
+}
+
+/// File extensions picked up when recursing into an input directory.
+const SCHEMA_EXTENSIONS: [&str; 5] = ["json", "jsonc", "json5", "yaml", "yml"];
+
+/// `*` and `?` never match a path separator; only `**` crosses directories.
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+  case_sensitive: true,
+  require_literal_separator: true,
+  require_literal_leading_dot: false,
+};
+
+fn compile_pattern(pattern: &str) -> Result<Pattern, BundleError> {
+  Pattern::new(pattern).map_err(|e| BundleError::InvalidPattern {
+    pattern: pattern.to_owned(),
+    message: e.msg.to_owned(),
+  })
+}
+
+fn is_glob(input: &str) -> bool {
+  input.contains(['*', '?', '['])
+}
+
+/// Patterns containing a `/` are matched against the whole path; others only
+/// against the file name.
+fn is_excluded(path: &Path, excludes: &[Pattern]) -> bool {
+  excludes
+    .iter()
+    .any(|pattern| match pattern.as_str().contains('/') {
+      true => pattern.matches_path_with(path, MATCH_OPTIONS),
+      false => path
+        .file_name()
+        .is_some_and(|name| pattern.matches_with(&name.to_string_lossy(), MATCH_OPTIONS)),
+    })
+}
+
+fn io_error(path: &Path, source: io::Error) -> BundleError {
+  BundleError::Io {
+    path: path.display().to_string(),
+    source,
+  }
+}
+
+/// Collect every file beneath `dir`, in sorted order, descending at most `depth`
+/// directories if given.
+///
+/// Symlinks are followed, but each directory is only visited once, so a symlink
+/// pointing back up the tree can't send the walk round in circles. Subdirectories
+/// that can't be read are skipped with a warning.
+fn walk_dir(
+  dir: &Path,
+  depth: Option<usize>,
+  visited: &mut HashSet<PathBuf>,
+  files: &mut Vec<PathBuf>,
+) -> Result<(), BundleError> {
+  let readable = match dir.as_os_str().is_empty() {
+    true => Path::new("."),
+    false => dir,
+  };
+  let canonical = readable.canonicalize().map_err(|e| io_error(dir, e))?;
+  if !visited.insert(canonical) {
+    debug!("Already visited «{}»; skipping it.", dir.display());
+    return Ok(());
+  }
+
+  let mut entries = fs::read_dir(readable)
+    .map_err(|e| io_error(dir, e))?
+    .map(|entry| entry.map(|e| dir.join(e.file_name())))
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|e| io_error(dir, e))?;
+  entries.sort();
+
+  for entry in entries {
+    // `metadata` follows symlinks, so a symlinked directory is walked like any other.
+    match fs::metadata(&entry) {
+      Ok(meta) if meta.is_dir() => {
+        if depth == Some(0) {
+          continue;
+        }
+        if let Err(e) = walk_dir(&entry, depth.map(|d| d - 1), visited, files) {
+          warn!("{e}; skipping it.");
+        }
+      }
+      Ok(_) => files.push(entry),
+      Err(e) => warn!("Skipping «{}»: {e}", entry.display()),
+    }
+  }
+
+  Ok(())
+}
+
+/// The directory a glob pattern should be walked from: every leading path component
+/// without any glob characters in it.
+fn glob_base(pattern: &str) -> PathBuf {
+  let mut base = PathBuf::new();
+  let mut components = pattern.split('/').peekable();
+  while let Some(component) = components.next() {
+    if is_glob(component) || components.peek().is_none() {
+      break;
+    }
+    base.push(if component.is_empty() { "/" } else { component });
+  }
+  base
+}
+
+/// How many directories below [`glob_base`] a glob pattern can match files in, or
+/// `None` when a `**` lets it match at any depth.
+fn glob_depth(pattern: &str) -> Option<usize> {
+  let components: Vec<&str> = pattern.split('/').collect();
+  if components.contains(&"**") {
+    return None;
+  }
+  let fixed = components
+    .iter()
+    .position(|c| is_glob(c))
+    .unwrap_or(components.len() - 1);
+  Some(components.len() - fixed - 1)
+}
+
+/// Expand the inputs into the list of files to read.
+///
+/// Each input can be a file, a directory or a glob pattern. Directories are walked
+/// recursively, picking up every `.json`, `.jsonc`, `.json5`, `.yaml` and `.yml`
+/// file. Glob patterns support `*`, `?`, `[...]` and `**`, which matches any number
+/// of directories; a pattern without `**` is only walked as deep as it can match.
+/// Files matching any of the `excludes` patterns are dropped; patterns without a `/`
+/// match the file name alone.
+///
+/// The files found for each input are sorted, and each file is only listed once,
+/// at the first input that produced it. An input of `-` stands for standard input,
+/// and is passed through as-is.
+pub fn expand_inputs(
+  inputs: &[String],
+  excludes: &[String],
+) -> Result<Vec<InputFile>, BundleError> {
+  let excludes = excludes
+    .iter()
+    .map(|e| compile_pattern(e))
+    .collect::<Result<Vec<_>, _>>()?;
+
+  let mut seen = HashSet::new();
+  let mut expanded = Vec::new();
+  for input in inputs {
+    let path = Path::new(input);
+    let mut found = Vec::new();
+    let mut root = PathBuf::new();
+
+    if input == STDIN_INPUT {
+      if seen.insert(path.to_path_buf()) {
+        expanded.push(InputFile {
+          path: path.to_path_buf(),
+          root,
+        });
+      }
+      continue;
+    }
+
+    if is_glob(input) {
+      let pattern = compile_pattern(input)?;
+      root = glob_base(input);
+      if root.as_os_str().is_empty() || root.is_dir() {
+        walk_dir(&root, glob_depth(input), &mut HashSet::new(), &mut found)?;
+      }
+      found.retain(|f| pattern.matches_path_with(f, MATCH_OPTIONS));
+    } else if path.is_dir() {
+      root = path.to_path_buf();
+      walk_dir(path, None, &mut HashSet::new(), &mut found)?;
+      found.retain(|f| {
+        f.extension()
+          .is_some_and(|ext| SCHEMA_EXTENSIONS.contains(&ext.to_string_lossy().as_ref()))
+      });
+    } else {
+      found.push(path.to_path_buf());
+    }
+
+    found.retain(|f| !is_excluded(f, &excludes));
+    if found.is_empty() {
+      warn!("The input «{input}» didn't match any files.");
+    }
+
+    for file in found {
+      if seen.insert(file.clone()) {
+        expanded.push(InputFile {
+          path: file,
+          root: root.clone(),
+        });
+      }
     }
-    Ok(val) => Some(val),
   }
+
+  Ok(expanded)
+}
+
+/// Read and parse every input, stopping at the first one that fails.
+///
+/// Inputs are expanded with [`expand_inputs`] first. Unless `format` is given, each
+/// document's format is detected separately.
+pub fn parse_inputs(
+  inputs: Vec<String>,
+  excludes: &[String],
+  format: Option<InputFormat>,
+) -> Result<Vec<InputDocument>, BundleError> {
+  expand_inputs(&inputs, excludes)?
+    .into_iter()
+    .map(|file| parse_one_file(file, format))
+    .collect()
 }
 
-pub fn parse_inputs(input_files: Vec<String>) -> Vec<serde_json::Value> {
-  let all_of_them: Vec<serde_json::Value> =
-    input_files.into_iter().filter_map(parse_one_file).collect();
+#[cfg(test)]
+mod tests {
+  use super::{expand_inputs, glob_depth, InputFile};
+  use crate::testing::scratch_dir;
+  use std::fs;
+  use std::path::{Path, PathBuf};
+
+  fn schema_tree(name: &str) -> PathBuf {
+    let dir = scratch_dir(name);
+    fs::create_dir_all(dir.join("schemas/common")).unwrap();
+    for file in [
+      "schemas/order.schema.json",
+      "schemas/notes.txt",
+      "schemas/common/money.schema.json",
+      "schemas/common/address.json",
+      "schemas/common/draft.schema.json",
+    ] {
+      fs::write(dir.join(file), "{}").unwrap();
+    }
+    dir
+  }
+
+  fn strip(dir: &Path, found: Vec<InputFile>) -> Vec<String> {
+    found
+      .into_iter()
+      .map(|f| f.path.strip_prefix(dir).unwrap().display().to_string())
+      .collect()
+  }
+
+  #[test]
+  fn test_directory_input_recurses_in_sorted_order() {
+    let dir = schema_tree("dir-input");
+    let input = dir.join("schemas").display().to_string();
 
-  all_of_them
+    let found = expand_inputs(std::slice::from_ref(&input), &[String::from("draft.*")]).unwrap();
+    assert!(found.iter().all(|f| f.root == Path::new(&input)));
+
+    assert_eq!(
+      strip(&dir, found),
+      vec![
+        "schemas/common/address.json",
+        "schemas/common/money.schema.json",
+        "schemas/order.schema.json",
+      ]
+    );
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn test_glob_input() {
+    let dir = schema_tree("glob-input");
+    let input = format!("{}/schemas/**/*.schema.json", dir.display());
+    let exclude = format!("{}/schemas/common/draft*", dir.display());
+
+    let found = expand_inputs(std::slice::from_ref(&input), &[exclude]).unwrap();
+    assert_eq!(
+      strip(&dir, found),
+      vec![
+        "schemas/common/money.schema.json",
+        "schemas/order.schema.json"
+      ]
+    );
+
+    let found = expand_inputs(&[input.replace("**/", "")], &[]).unwrap();
+    assert_eq!(strip(&dir, found), vec!["schemas/order.schema.json"]);
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn test_globs_are_only_walked_as_deep_as_they_match() {
+    assert_eq!(glob_depth("*.json"), Some(0));
+    assert_eq!(glob_depth("schemas/*.json"), Some(0));
+    assert_eq!(glob_depth("schemas/*/v1/*.json"), Some(2));
+    assert_eq!(glob_depth("/abs/schemas/order.json"), Some(0));
+    assert_eq!(glob_depth("schemas/**/*.json"), None);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn test_symlink_loops_are_walked_once() {
+    let dir = schema_tree("symlink-loop");
+    std::os::unix::fs::symlink(dir.join("schemas"), dir.join("schemas/common/loop")).unwrap();
+    let input = dir.join("schemas").display().to_string();
+
+    let found = expand_inputs(&[input.clone(), input], &[]).unwrap();
+
+    assert_eq!(found.len(), 4);
+    fs::remove_dir_all(dir).unwrap();
+  }
 }
@@ -0,0 +1,682 @@
+use serde_json::{Map, Value as JsonValue};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use url::Url;
+
+use crate::bundler::{SchemaId, SchemaMap};
+use crate::dialect::Dialect;
+use crate::pointer::{decode_fragment, encode_fragment, escape_token};
+use crate::resolver::{
+  base_identifier, find_embedded_resources, resolve_reference, DATA_KEYWORDS,
+  NAMED_SUBSCHEMA_KEYWORDS,
+};
+
+/// The `$dynamicAnchor` that replaces `"$recursiveAnchor": true`.
+const RECURSIVE_ANCHOR: &str = "meta";
+
+/// Something [`migrate`] couldn't convert, or converted in a way worth checking.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationProblem {
+  /// JSON Pointer to the subschema in the original document.
+  pub location: String,
+  pub message: String,
+}
+
+impl fmt::Display for MigrationProblem {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "at «{}»: {}", self.location, self.message)
+  }
+}
+
+/// A document rewritten for JSON Schema 2020-12.
+#[derive(Debug, Clone)]
+pub struct Migration {
+  pub schema: JsonValue,
+  pub problems: Vec<MigrationProblem>,
+  /// Where each subschema moved to, keyed by its JSON Pointer in the original document.
+  moves: BTreeMap<String, String>,
+  /// Every `$ref`, by the location of the subschema holding it in the migrated document,
+  /// with the absolute URI it resolves to.
+  refs: Vec<(String, Url)>,
+}
+
+impl Migration {
+  /// Where a JSON Pointer into the original document points in the migrated one.
+  ///
+  /// # Examples
+  ///
+  /// ```rust
+  /// # use bundle_schema::dialect::Dialect;
+  /// # use bundle_schema::migrate::migrate;
+  /// let schema = serde_json::json!({"definitions": {"pair": {"items": [true, false]}}});
+  /// let migration = migrate(&schema, Dialect::Draft07);
+  /// assert_eq!(migration.relocate("/definitions/pair/items/1"), "/$defs/pair/prefixItems/1");
+  /// assert_eq!(migration.relocate("/definitions/pair/not/a/schema"), "/$defs/pair/not/a/schema");
+  /// ```
+  pub fn relocate(&self, pointer: &str) -> String {
+    let mut prefix = pointer;
+    loop {
+      if let Some(moved) = self.moves.get(prefix) {
+        return format!("{moved}{}", &pointer[prefix.len()..]);
+      }
+      match prefix.rfind('/') {
+        Some(idx) => prefix = &prefix[..idx],
+        None => return pointer.to_owned(),
+      }
+    }
+  }
+}
+
+/// Rewrite a single document in `dialect`, or the one its `$schema` names, for JSON
+/// Schema 2020-12:
+///
+/// - `id` becomes `$id`, and an identifier that's only a fragment becomes an `$anchor`;
+/// - `definitions` becomes `$defs`;
+/// - an array `items` becomes `prefixItems`, and `additionalItems` becomes `items`;
+/// - `dependencies` is split into `dependentRequired` and `dependentSchemas`;
+/// - a boolean `exclusiveMinimum` or `exclusiveMaximum` takes the value of `minimum`
+///   or `maximum`;
+/// - `$recursiveRef` and `$recursiveAnchor` become `$dynamicRef` and `$dynamicAnchor`,
+///   or a `$recursiveRef` becomes a plain `$ref` in a resource without a `$recursiveAnchor`;
+/// - `$schema` names 2020-12.
+///
+/// References pointing into the document are rewritten to follow what moved.
+/// Anything that can't be converted is left as it is and reported.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::dialect::Dialect;
+/// # use bundle_schema::migrate::migrate;
+/// let schema = serde_json::json!({
+///   "$schema": "http://json-schema.org/draft-04/schema#",
+///   "id": "https://foo.com/order.json",
+///   "properties": {
+///     "total": {"type": "number", "minimum": 0, "exclusiveMinimum": true},
+///     "lines": {"items": [{"$ref": "#/definitions/line"}], "additionalItems": false}
+///   },
+///   "dependencies": {"total": ["lines"], "lines": {"required": ["total"]}},
+///   "definitions": {"line": {"type": "string"}}
+/// });
+///
+/// let migration = migrate(&schema, Dialect::Draft2020_12);
+/// assert!(migration.problems.is_empty());
+/// assert_eq!(
+///   migration.schema,
+///   serde_json::json!({
+///     "$schema": "https://json-schema.org/draft/2020-12/schema",
+///     "$id": "https://foo.com/order.json",
+///     "properties": {
+///       "total": {"type": "number", "exclusiveMinimum": 0},
+///       "lines": {"prefixItems": [{"$ref": "#/$defs/line"}], "items": false}
+///     },
+///     "dependentRequired": {"total": ["lines"]},
+///     "dependentSchemas": {"lines": {"required": ["total"]}},
+///     "$defs": {"line": {"type": "string"}}
+///   })
+/// );
+/// ```
+pub fn migrate(schema: &JsonValue, dialect: Dialect) -> Migration {
+  let dialect = Dialect::detect(schema, dialect);
+  let id = SchemaId::from_json_value_as(schema, dialect)
+    .ok()
+    .map(|id| id.full_id);
+
+  let mut migration = restructure(schema, id.as_ref(), dialect);
+  if let Some(id) = &id {
+    let mut documents = HashMap::new();
+    documents.insert(id.clone(), (id.clone(), String::new()));
+    for nested in find_embedded_resources(schema, id, dialect) {
+      documents.insert(nested.id, (id.clone(), nested.location));
+    }
+    let rewrites = moved_refs(&migration, &documents, |doc| {
+      (doc == id).then_some(&migration)
+    });
+    apply_rewrites(&mut migration, rewrites);
+  }
+
+  migration
+}
+
+/// [`migrate`] every top-level document registered in `schemas`, keyed by its `$id`.
+///
+/// References between the documents are rewritten too, so one pointing into another
+/// document's `definitions` follows them to `$defs`.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::bundler::SchemaMap;
+/// # use bundle_schema::migrate::migrate_registered;
+/// let mut registry = SchemaMap::new();
+/// registry
+///   .register_schema(serde_json::json!({
+///     "$schema": "http://json-schema.org/draft-07/schema#",
+///     "$id": "https://foo.com/common.json",
+///     "definitions": {"money": {"type": "number"}}
+///   }))
+///   .unwrap();
+/// registry
+///   .register_schema(serde_json::json!({
+///     "$id": "https://foo.com/order.json",
+///     "properties": {"total": {"$ref": "common.json#/definitions/money"}}
+///   }))
+///   .unwrap();
+///
+/// let migrations = migrate_registered(&registry);
+/// let order = &migrations[&url::Url::parse("https://foo.com/order.json").unwrap()];
+/// assert_eq!(order.schema["properties"]["total"]["$ref"], "common.json#/$defs/money");
+/// ```
+pub fn migrate_registered(schemas: &SchemaMap) -> BTreeMap<Url, Migration> {
+  let mut migrations: BTreeMap<Url, Migration> = schemas
+    .registry
+    .values()
+    .filter(|item| item.parent.is_none())
+    .map(|item| {
+      let migration = restructure(&item.node, Some(&item.id.full_id), item.dialect);
+      (item.id.full_id.clone(), migration)
+    })
+    .collect();
+
+  // Where every registered resource lives: its top-level document and its location there.
+  let documents: HashMap<Url, (Url, String)> = schemas
+    .registry
+    .values()
+    .map(|item| {
+      let document = item
+        .parent
+        .clone()
+        .unwrap_or_else(|| item.id.full_id.clone());
+      let location = item.location.clone().unwrap_or_default();
+      (item.id.full_id.clone(), (document, location))
+    })
+    .collect();
+
+  let rewrites: Vec<(Url, Vec<(String, String)>)> = migrations
+    .iter()
+    .map(|(id, migration)| {
+      let rewrites = moved_refs(migration, &documents, |doc| migrations.get(doc));
+      (id.clone(), rewrites)
+    })
+    .collect();
+  for (id, rewrites) in rewrites {
+    if let Some(migration) = migrations.get_mut(&id) {
+      apply_rewrites(migration, rewrites);
+    }
+  }
+
+  migrations
+}
+
+/// Restructure every registered document holding a resource written before 2020-12,
+/// keyed by its `$id`. References are left as they are, pointing into the original
+/// documents, for [`Migration::relocate`] to follow.
+pub(crate) fn restructure_registered(schemas: &SchemaMap) -> BTreeMap<Url, Migration> {
+  let documents: BTreeSet<&Url> = schemas
+    .registry
+    .values()
+    .filter(|item| item.dialect < Dialect::Draft2020_12)
+    .map(|item| item.parent.as_ref().unwrap_or(&item.id.full_id))
+    .collect();
+
+  documents
+    .into_iter()
+    .filter_map(|id| {
+      let item = schemas.registry.get(id)?;
+      let migration = restructure(&item.node, Some(id), item.dialect);
+      Some((id.clone(), migration))
+    })
+    .collect()
+}
+
+/// The new value of every `$ref` in `migration` whose pointer fragment points at
+/// something that moved, keyed by the location of the subschema holding it.
+///
+/// `documents` maps each resource's URI onto the `$id` of the document holding it and
+/// its location there, and `migration_of` finds the migration of such a document.
+fn moved_refs<'a>(
+  migration: &Migration,
+  documents: &HashMap<Url, (Url, String)>,
+  migration_of: impl Fn(&Url) -> Option<&'a Migration>,
+) -> Vec<(String, String)> {
+  let mut rewrites = Vec::new();
+  for (location, target) in &migration.refs {
+    let Some(fragment) = target.fragment().and_then(|f| decode_fragment(f).ok()) else {
+      continue;
+    };
+    if !fragment.starts_with('/') {
+      continue;
+    }
+    let mut resource = target.clone();
+    resource.set_fragment(None);
+    let Some((document, resource_location)) = documents.get(&resource) else {
+      continue;
+    };
+    let Some(target_migration) = migration_of(document) else {
+      continue;
+    };
+
+    let new_resource = target_migration.relocate(resource_location);
+    let new_target = target_migration.relocate(&format!("{resource_location}{fragment}"));
+    let Some(new_fragment) = new_target.strip_prefix(&new_resource) else {
+      continue;
+    };
+    if new_fragment == fragment {
+      continue;
+    }
+
+    let pointer = format!("{location}/$ref");
+    if let Some(JsonValue::String(reference)) = migration.schema.pointer(&pointer) {
+      let without_fragment = reference.split('#').next().unwrap_or_default();
+      let rewritten = format!("{without_fragment}#{}", encode_fragment(new_fragment));
+      rewrites.push((pointer, rewritten));
+    }
+  }
+  rewrites
+}
+
+/// Replace the `$ref`s found by [`moved_refs`].
+fn apply_rewrites(migration: &mut Migration, rewrites: Vec<(String, String)>) {
+  for (pointer, rewritten) in rewrites {
+    if let Some(reference) = migration.schema.pointer_mut(&pointer) {
+      *reference = JsonValue::String(rewritten);
+    }
+  }
+}
+
+/// Rewrite draft-04's boolean `exclusiveMinimum` and `exclusiveMaximum` in a subschema
+/// into the numeric form draft-06 replaced them with, taking the value of `minimum` or
+/// `maximum`. Returns a message for each one left as it is, for want of a numeric bound.
+pub(crate) fn numeric_exclusive_bounds(map: &mut Map<String, JsonValue>) -> Vec<String> {
+  let mut unconverted = Vec::new();
+  for (exclusive, bound) in [
+    ("exclusiveMinimum", "minimum"),
+    ("exclusiveMaximum", "maximum"),
+  ] {
+    let limit = map.get(bound).filter(|limit| limit.is_number()).cloned();
+    match (map.get(exclusive).and_then(JsonValue::as_bool), limit) {
+      (None, _) => {}
+      (Some(false), _) => {
+        map.remove(exclusive);
+      }
+      (Some(true), Some(limit)) => {
+        map.remove(bound);
+        map.insert(exclusive.to_owned(), limit);
+      }
+      (Some(true), None) => unconverted.push(format!(
+        "`{exclusive}` is true, but there's no numeric `{bound}`; leaving it as it is"
+      )),
+    }
+  }
+  unconverted
+}
+
+/// Everything [`migrate`] does apart from rewriting references.
+fn restructure(schema: &JsonValue, id: Option<&Url>, dialect: Dialect) -> Migration {
+  let mut migrator = Migrator {
+    problems: Vec::new(),
+    moves: BTreeMap::new(),
+    refs: Vec::new(),
+    recursive_anchor: false,
+  };
+  let migrated = migrator.schema(schema, id, dialect, String::new(), String::new());
+
+  Migration {
+    schema: migrated,
+    problems: migrator.problems,
+    moves: migrator.moves,
+    refs: migrator.refs,
+  }
+}
+
+struct Migrator {
+  problems: Vec<MigrationProblem>,
+  moves: BTreeMap<String, String>,
+  refs: Vec<(String, Url)>,
+  /// Whether the resource being migrated declares `"$recursiveAnchor": true`.
+  recursive_anchor: bool,
+}
+
+impl Migrator {
+  fn problem(&mut self, location: &str, message: impl Into<String>) {
+    self.problems.push(MigrationProblem {
+      location: location.to_owned(),
+      message: message.into(),
+    });
+  }
+
+  /// Migrate a subschema found at `old` in the original document, which ends up at `new`.
+  fn schema(
+    &mut self,
+    node: &JsonValue,
+    base: Option<&Url>,
+    dialect: Dialect,
+    old: String,
+    new: String,
+  ) -> JsonValue {
+    self.moves.insert(old.clone(), new.clone());
+    let JsonValue::Object(map) = node else {
+      return node.clone();
+    };
+
+    let dialect = dialect.within(node);
+    let own_base = match base_identifier(map, dialect) {
+      Some(id) => resolve_reference(base, id).ok(),
+      None => base.cloned(),
+    };
+    let outer_recursive_anchor = self.recursive_anchor;
+    if old.is_empty() || base_identifier(map, dialect).is_some() {
+      self.recursive_anchor = map.get("$recursiveAnchor") == Some(&JsonValue::Bool(true));
+    }
+    if dialect.ignores_ref_siblings()
+      && map.get("$ref").is_some_and(JsonValue::is_string)
+      && map.len() > 1
+    {
+      self.problem(
+        &old,
+        "keywords beside `$ref` were ignored, but apply from 2019-09 on",
+      );
+    }
+
+    let child = |key: &str| {
+      (
+        format!("{old}/{}", escape_token(key)),
+        format!("{new}/{}", escape_token(key)),
+      )
+    };
+    let items_is_array = map.get("items").is_some_and(JsonValue::is_array);
+    let mut out = Map::new();
+
+    for (key, value) in map {
+      let (old_child, new_child) = child(key);
+      match key.as_str() {
+        k if DATA_KEYWORDS.contains(&k) => {
+          out.insert(key.clone(), value.clone());
+        }
+        "$schema" => match value.as_str().and_then(Dialect::from_schema_uri) {
+          Some(_) => {
+            out.insert(
+              key.clone(),
+              JsonValue::from(Dialect::Draft2020_12.schema_uri()),
+            );
+          }
+          None => {
+            self.problem(
+              &old,
+              format!("unknown `$schema` {value}; leaving it as it is"),
+            );
+            out.insert(key.clone(), value.clone());
+          }
+        },
+        k if k == dialect.id_keyword() && value.is_string() => {
+          let id = value.as_str().unwrap_or_default();
+          let (resource, anchor) = match dialect.has_fragment_ids() {
+            true => id.split_once('#').unwrap_or((id, "")),
+            false => (id, ""),
+          };
+          if !resource.is_empty() {
+            out.insert(String::from("$id"), JsonValue::from(resource));
+          }
+          if !anchor.is_empty() {
+            out.insert(String::from("$anchor"), JsonValue::from(anchor));
+          }
+        }
+        "$ref" => {
+          if let Some(reference) = value.as_str() {
+            if let Ok(target) = resolve_reference(own_base.as_ref(), reference) {
+              self.refs.push((new.clone(), target));
+            }
+          }
+          out.insert(key.clone(), value.clone());
+        }
+        "$recursiveRef" if value.as_str() == Some("#") => {
+          let (keyword, reference) = match (self.recursive_anchor, map.contains_key("$ref")) {
+            (true, _) => ("$dynamicRef", format!("#{RECURSIVE_ANCHOR}")),
+            // Without a `$recursiveAnchor` it's a plain reference to the resource's root.
+            (false, false) => ("$ref", String::from("#")),
+            // A `$dynamicRef` to a fragment that isn't a `$dynamicAnchor` acts like a `$ref`.
+            (false, true) => ("$dynamicRef", String::from("#")),
+          };
+          out.insert(String::from(keyword), JsonValue::from(reference));
+        }
+        "$recursiveRef" => {
+          self.problem(
+            &old,
+            format!("`$recursiveRef` {value} isn't `#`; copying it to `$dynamicRef` as it is"),
+          );
+          out.insert(String::from("$dynamicRef"), value.clone());
+        }
+        "$recursiveAnchor" => match value.as_bool() {
+          Some(true) => {
+            out.insert(
+              String::from("$dynamicAnchor"),
+              JsonValue::from(RECURSIVE_ANCHOR),
+            );
+          }
+          Some(false) => {}
+          None => self.problem(&old, format!("`$recursiveAnchor` {value} isn't a boolean")),
+        },
+        "definitions" if value.is_object() => {
+          let defs = out
+            .entry("$defs")
+            .or_insert_with(|| JsonValue::Object(Map::new()));
+          for (name, subschema) in value.as_object().into_iter().flatten() {
+            let old_def = format!("{old_child}/{}", escape_token(name));
+            let new_def = format!("{new}/$defs/{}", escape_token(name));
+            let migrated = self.schema(subschema, own_base.as_ref(), dialect, old_def, new_def);
+            match defs.as_object_mut() {
+              Some(defs) if !defs.contains_key(name) => {
+                defs.insert(name.clone(), migrated);
+              }
+              _ => self.problem(
+                &old,
+                format!("both `definitions` and `$defs` define «{name}»; keeping `$defs`"),
+              ),
+            }
+          }
+        }
+        "items" if value.is_array() => {
+          let items = self.array(
+            value,
+            own_base.as_ref(),
+            dialect,
+            &old_child,
+            &format!("{new}/prefixItems"),
+          );
+          out.insert(String::from("prefixItems"), items);
+        }
+        "additionalItems" => {
+          if items_is_array {
+            let migrated = self.schema(
+              value,
+              own_base.as_ref(),
+              dialect,
+              old_child,
+              format!("{new}/items"),
+            );
+            out.insert(String::from("items"), migrated);
+          } else {
+            self.problem(
+              &old,
+              "dropping `additionalItems`, which had no effect without an array `items`",
+            );
+          }
+        }
+        "dependencies" if value.is_object() => {
+          for (name, dependency) in value.as_object().into_iter().flatten() {
+            let old_dep = format!("{old_child}/{}", escape_token(name));
+            let (keyword, migrated) = match dependency {
+              JsonValue::Array(_) => ("dependentRequired", dependency.clone()),
+              JsonValue::Object(_) | JsonValue::Bool(_) => {
+                let new_dep = format!("{new}/dependentSchemas/{}", escape_token(name));
+                let migrated =
+                  self.schema(dependency, own_base.as_ref(), dialect, old_dep, new_dep);
+                ("dependentSchemas", migrated)
+              }
+              _ => {
+                self.problem(
+                  &old_dep,
+                  format!("dependency {dependency} is neither a list of properties nor a schema"),
+                );
+                continue;
+              }
+            };
+            let dependents = out
+              .entry(keyword)
+              .or_insert_with(|| JsonValue::Object(Map::new()));
+            if let Some(dependents) = dependents.as_object_mut() {
+              dependents.insert(name.clone(), migrated);
+            }
+          }
+        }
+        k if NAMED_SUBSCHEMA_KEYWORDS.contains(&k) && value.is_object() => {
+          let mut named = Map::new();
+          for (name, subschema) in value.as_object().into_iter().flatten() {
+            let old_named = format!("{old_child}/{}", escape_token(name));
+            let new_named = format!("{new_child}/{}", escape_token(name));
+            named.insert(
+              name.clone(),
+              self.schema(subschema, own_base.as_ref(), dialect, old_named, new_named),
+            );
+          }
+          out.insert(key.clone(), JsonValue::Object(named));
+        }
+        _ => match value {
+          JsonValue::Object(_) => {
+            let migrated = self.schema(value, own_base.as_ref(), dialect, old_child, new_child);
+            out.insert(key.clone(), migrated);
+          }
+          JsonValue::Array(_) => {
+            let migrated = self.array(value, own_base.as_ref(), dialect, &old_child, &new_child);
+            out.insert(key.clone(), migrated);
+          }
+          _ => {
+            out.insert(key.clone(), value.clone());
+          }
+        },
+      }
+    }
+
+    for message in numeric_exclusive_bounds(&mut out) {
+      self.problem(&old, message);
+    }
+
+    self.recursive_anchor = outer_recursive_anchor;
+    JsonValue::Object(out)
+  }
+
+  /// Migrate an array of subschemas, like `allOf`.
+  fn array(
+    &mut self,
+    value: &JsonValue,
+    base: Option<&Url>,
+    dialect: Dialect,
+    old: &str,
+    new: &str,
+  ) -> JsonValue {
+    let items = value.as_array().into_iter().flatten().enumerate();
+    JsonValue::Array(
+      items
+        .map(|(idx, item)| match item {
+          JsonValue::Object(_) | JsonValue::Bool(_) => self.schema(
+            item,
+            base,
+            dialect,
+            format!("{old}/{idx}"),
+            format!("{new}/{idx}"),
+          ),
+          _ => item.clone(),
+        })
+        .collect(),
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_migration_reports_what_it_cannot_convert() {
+    let schema = json!({
+      "$schema": "https://json-schema.org/draft/2019-09/schema",
+      "$id": "https://foo.com/tree.json",
+      "$recursiveAnchor": true,
+      "properties": {
+        "children": {"items": {"$recursiveRef": "#"}},
+        "parent": {"$recursiveRef": "tree.json"},
+        "odd": {"exclusiveMaximum": true},
+        "loose": {"items": {"type": "string"}, "additionalItems": false}
+      }
+    });
+
+    let migration = migrate(&schema, Dialect::Draft2020_12);
+    assert_eq!(
+      migration.schema,
+      json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "$id": "https://foo.com/tree.json",
+        "$dynamicAnchor": "meta",
+        "properties": {
+          "children": {"items": {"$dynamicRef": "#meta"}},
+          "parent": {"$dynamicRef": "tree.json"},
+          "odd": {"exclusiveMaximum": true},
+          "loose": {"items": {"type": "string"}}
+        }
+      })
+    );
+
+    let locations: Vec<&str> = migration
+      .problems
+      .iter()
+      .map(|p| p.location.as_str())
+      .collect();
+    assert_eq!(
+      locations,
+      ["/properties/loose", "/properties/odd", "/properties/parent"]
+    );
+  }
+
+  #[test]
+  fn test_recursive_refs_without_a_recursive_anchor_become_plain_refs() {
+    let schema = json!({
+      "$schema": "https://json-schema.org/draft/2019-09/schema",
+      "$id": "https://foo.com/list.json",
+      "items": {"$recursiveRef": "#"},
+      "$defs": {
+        "tree": {
+          "$id": "tree.json",
+          "$recursiveAnchor": true,
+          "items": {"$recursiveRef": "#"}
+        }
+      }
+    });
+
+    let migration = migrate(&schema, Dialect::Draft2020_12);
+    assert!(migration.problems.is_empty());
+    assert_eq!(migration.schema["items"], json!({"$ref": "#"}));
+    assert_eq!(
+      migration.schema["$defs"]["tree"]["items"],
+      json!({"$dynamicRef": "#meta"})
+    );
+  }
+
+  #[test]
+  fn test_fragment_ids_become_anchors_and_ref_siblings_are_reported() {
+    let schema = json!({
+      "$id": "https://foo.com/a.json",
+      "definitions": {"b": {"$id": "#b", "type": "string"}},
+      "properties": {"c": {"$ref": "#b", "description": "ignored before 2019-09"}}
+    });
+
+    let migration = migrate(&schema, Dialect::Draft07);
+    assert_eq!(
+      migration.schema["$defs"]["b"],
+      json!({"$anchor": "b", "type": "string"})
+    );
+    assert_eq!(migration.schema["properties"]["c"]["$ref"], "#b");
+    assert_eq!(migration.problems.len(), 1);
+    assert_eq!(migration.problems[0].location, "/properties/c");
+  }
+}
@@ -1,3 +1,17 @@
 pub mod bundler;
+pub mod cache;
+pub mod dereference;
+pub mod dialect;
+pub mod error;
+pub mod formats;
 pub mod inputs;
 pub mod logging;
+pub mod migrate;
+pub mod output;
+pub mod pointer;
+pub mod prune;
+pub mod resolver;
+pub mod retriever;
+
+#[cfg(test)]
+pub(crate) mod testing;
@@ -0,0 +1,206 @@
+use log::debug;
+use serde::Serialize;
+use serde_json::ser::{PrettyFormatter, Serializer};
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::bundler::SchemaId;
+
+/// How the bundled output should be laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputStyle {
+  /// One key per line, indented by the given number of spaces.
+  Pretty { indent: usize },
+  /// Everything on a single line with no extra whitespace.
+  Compact,
+}
+
+impl Default for OutputStyle {
+  fn default() -> Self {
+    OutputStyle::Pretty { indent: 2 }
+  }
+}
+
+/// Serialize a JSON value according to the requested style.
+///
+/// The rendered output always ends with a newline.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::output::{render, OutputStyle};
+/// let value = serde_json::json!({"type": "string"});
+/// assert_eq!(render(&value, OutputStyle::Compact), "{\"type\":\"string\"}\n");
+/// assert_eq!(
+///   render(&value, OutputStyle::Pretty { indent: 4 }),
+///   "{\n    \"type\": \"string\"\n}\n"
+/// );
+/// ```
+pub fn render(value: &JsonValue, style: OutputStyle) -> String {
+  let mut rendered = match style {
+    OutputStyle::Compact => serde_json::to_vec(value).expect("JSON values always serialize"),
+    OutputStyle::Pretty { indent } => {
+      let indent = " ".repeat(indent);
+      let mut buf = Vec::new();
+      let mut ser =
+        Serializer::with_formatter(&mut buf, PrettyFormatter::with_indent(indent.as_bytes()));
+      value
+        .serialize(&mut ser)
+        .expect("JSON values always serialize");
+      buf
+    }
+  };
+  rendered.push(b'\n');
+
+  String::from_utf8(rendered).expect("serde_json only produces UTF-8")
+}
+
+/// Write a JSON value to `destination`, or to stdout when it is `None` or `-`.
+///
+/// Files are written atomically: the output goes to a temporary file in the same
+/// directory, which is then renamed over the destination. A failed run never leaves
+/// a half-written schema behind.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::output::{write_output, OutputStyle};
+/// let dest = std::env::temp_dir().join("bundle-schema-write-output-doctest.json");
+/// let value = serde_json::json!({"type": "string"});
+/// write_output(&value, Some(dest.to_str().unwrap()), OutputStyle::Compact).unwrap();
+/// assert_eq!(std::fs::read_to_string(&dest).unwrap(), "{\"type\":\"string\"}\n");
+/// # std::fs::remove_file(&dest).unwrap();
+/// ```
+pub fn write_output(
+  value: &JsonValue,
+  destination: Option<&str>,
+  style: OutputStyle,
+) -> io::Result<()> {
+  let rendered = render(value, style);
+
+  match destination {
+    None | Some("-") => {
+      debug!("Writing output to stdout");
+      let mut stdout = io::stdout().lock();
+      stdout.write_all(rendered.as_bytes())?;
+      stdout.flush()
+    }
+    Some(path) => {
+      debug!("Writing output to «{path}»");
+      write_atomically(Path::new(path), rendered.as_bytes())
+    }
+  }
+}
+
+/// Work out where, within an output directory, the bundle for a root schema goes.
+///
+/// The path mirrors the root's `relative_id`. Anything that could escape the output
+/// directory is dropped, and a root without a usable path is named `index.json`.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::bundler::SchemaId;
+/// # use bundle_schema::output::bundle_path;
+/// # use std::path::Path;
+/// let id = SchemaId::from_url(url::Url::parse("https://foo.com/api/v1/order.json").unwrap());
+/// assert_eq!(bundle_path(Path::new("dist"), &id), Path::new("dist/api/v1/order.json"));
+///
+/// let id = SchemaId::from_url(url::Url::parse("https://foo.com/").unwrap());
+/// assert_eq!(bundle_path(Path::new("dist"), &id), Path::new("dist/index.json"));
+/// ```
+pub fn bundle_path(out_dir: &Path, root_id: &SchemaId) -> PathBuf {
+  let relative: PathBuf = Path::new(&root_id.relative_id)
+    .components()
+    .filter(|c| matches!(c, Component::Normal(_)))
+    .collect();
+
+  match relative.as_os_str().is_empty() || root_id.relative_id.ends_with('/') {
+    true => out_dir.join(relative).join("index.json"),
+    false => out_dir.join(relative),
+  }
+}
+
+/// Find two roots whose bundles [`bundle_path`] would put at the same path, as happens
+/// to roots sharing a `relative_id` on different hosts.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::bundler::SchemaId;
+/// # use bundle_schema::output::find_path_clash;
+/// # use std::path::Path;
+/// let a = SchemaId::from_url(url::Url::parse("https://a.com/api/order.json").unwrap());
+/// let b = SchemaId::from_url(url::Url::parse("https://b.com/api/order.json").unwrap());
+/// let c = SchemaId::from_url(url::Url::parse("https://b.com/api/user.json").unwrap());
+///
+/// let (path, first, second) = find_path_clash(Path::new("dist"), [&a, &c, &b]).unwrap();
+/// assert_eq!(path, Path::new("dist/api/order.json"));
+/// assert_eq!((&first.full_id, &second.full_id), (&a.full_id, &b.full_id));
+/// assert!(find_path_clash(Path::new("dist"), [&a, &c]).is_none());
+/// ```
+pub fn find_path_clash<'a>(
+  out_dir: &Path,
+  root_ids: impl IntoIterator<Item = &'a SchemaId>,
+) -> Option<(PathBuf, &'a SchemaId, &'a SchemaId)> {
+  let mut seen: HashMap<PathBuf, &SchemaId> = HashMap::new();
+  for root_id in root_ids {
+    let path = bundle_path(out_dir, root_id);
+    match seen.get(&path) {
+      Some(first) if first.full_id != root_id.full_id => return Some((path, first, root_id)),
+      Some(_) => {}
+      None => {
+        seen.insert(path, root_id);
+      }
+    }
+  }
+  None
+}
+
+/// Write a bundle into an output directory, at the path given by [`bundle_path`],
+/// creating any missing directories along the way. Returns the path written to.
+pub fn write_to_dir(
+  value: &JsonValue,
+  out_dir: &Path,
+  root_id: &SchemaId,
+  style: OutputStyle,
+) -> io::Result<PathBuf> {
+  let path = bundle_path(out_dir, root_id);
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent)?;
+  }
+
+  debug!("Writing output to «{}»", path.display());
+  write_atomically(&path, render(value, style).as_bytes())?;
+
+  Ok(path)
+}
+
+fn temp_path_for(path: &Path) -> PathBuf {
+  let file_name = path
+    .file_name()
+    .map(|n| n.to_string_lossy().into_owned())
+    .unwrap_or_else(|| String::from("output"));
+
+  path.with_file_name(format!(".{file_name}.{}.tmp", std::process::id()))
+}
+
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+  let temp_path = temp_path_for(path);
+
+  let result = File::create(&temp_path)
+    .and_then(|mut fh| {
+      fh.write_all(contents)?;
+      fh.sync_all()
+    })
+    .and_then(|_| fs::rename(&temp_path, path));
+
+  if result.is_err() {
+    let _ = fs::remove_file(&temp_path);
+  }
+
+  result
+}
@@ -0,0 +1,216 @@
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use serde_json::Value as JsonValue;
+use std::fmt;
+
+/// Why a JSON Pointer (RFC 6901) couldn't be evaluated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PointerError {
+  /// The pointer is neither empty nor starts with `/`.
+  Malformed(String),
+  /// The fragment holding the pointer isn't valid percent-encoded UTF-8.
+  BadEncoding(String),
+  /// The pointer walks through a location that doesn't exist in the document.
+  NotFound {
+    /// The full pointer being evaluated.
+    pointer: String,
+    /// The portion of the pointer that was successfully evaluated before failing.
+    resolved: String,
+    /// The reference token that couldn't be found.
+    token: String,
+  },
+  /// A plain-name fragment that no `$anchor` or `$dynamicAnchor` declares.
+  UnknownAnchor(String),
+}
+
+impl fmt::Display for PointerError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      PointerError::Malformed(p) => write!(f, "JSON Pointer «{p}» must be empty or start with `/`"),
+      PointerError::BadEncoding(p) => {
+        write!(f, "fragment «{p}» is not valid percent-encoded UTF-8")
+      }
+      PointerError::NotFound {
+        pointer,
+        resolved,
+        token,
+      } => write!(
+        f,
+        "JSON Pointer «{pointer}» does not exist: no «{token}» under «{resolved}»"
+      ),
+      PointerError::UnknownAnchor(name) => write!(f, "no anchor named «{name}» is declared"),
+    }
+  }
+}
+
+impl std::error::Error for PointerError {}
+
+/// Escape a single reference token so it can be appended to a JSON Pointer.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::pointer::escape_token;
+/// assert_eq!(escape_token("a/b~c"), "a~1b~0c");
+/// ```
+pub fn escape_token(token: &str) -> String {
+  token.replace('~', "~0").replace('/', "~1")
+}
+
+/// Undo [`escape_token`]. `~1` is replaced before `~0`, so `~01` becomes `~1`.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::pointer::unescape_token;
+/// assert_eq!(unescape_token("a~1b~0c"), "a/b~c");
+/// assert_eq!(unescape_token("~01"), "~1");
+/// ```
+pub fn unescape_token(token: &str) -> String {
+  token.replace("~1", "/").replace("~0", "~")
+}
+
+/// Split a JSON Pointer into its unescaped reference tokens.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::pointer::parse_pointer;
+/// assert_eq!(parse_pointer("").unwrap(), Vec::<String>::new());
+/// assert_eq!(parse_pointer("/$defs/a~1b").unwrap(), vec!["$defs", "a/b"]);
+/// assert!(parse_pointer("$defs").is_err());
+/// ```
+pub fn parse_pointer(pointer: &str) -> Result<Vec<String>, PointerError> {
+  if pointer.is_empty() {
+    return Ok(Vec::new());
+  }
+
+  match pointer.strip_prefix('/') {
+    Some(rest) => Ok(rest.split('/').map(unescape_token).collect()),
+    None => Err(PointerError::Malformed(pointer.to_owned())),
+  }
+}
+
+/// Evaluate a JSON Pointer against a document.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::pointer::resolve_pointer;
+/// let schema = serde_json::json!({
+///   "$defs": {"Money": {"type": "number"}},
+///   "prefixItems": [{"type": "string"}]
+/// });
+/// assert_eq!(resolve_pointer(&schema, "/$defs/Money").unwrap()["type"], "number");
+/// assert_eq!(resolve_pointer(&schema, "/prefixItems/0").unwrap()["type"], "string");
+/// assert_eq!(resolve_pointer(&schema, "").unwrap(), &schema);
+///
+/// let err = resolve_pointer(&schema, "/$defs/Currency").unwrap_err();
+/// assert_eq!(
+///   err.to_string(),
+///   "JSON Pointer «/$defs/Currency» does not exist: no «Currency» under «/$defs»"
+/// );
+/// ```
+pub fn resolve_pointer<'a>(
+  node: &'a JsonValue,
+  pointer: &str,
+) -> Result<&'a JsonValue, PointerError> {
+  let mut current = node;
+  let mut resolved = String::new();
+
+  for token in parse_pointer(pointer)? {
+    let next = match current {
+      JsonValue::Object(map) => map.get(&token),
+      JsonValue::Array(items) => parse_index(&token).and_then(|idx| items.get(idx)),
+      _ => None,
+    };
+
+    current = match next {
+      Some(n) => n,
+      None => {
+        return Err(PointerError::NotFound {
+          pointer: pointer.to_owned(),
+          resolved,
+          token,
+        })
+      }
+    };
+    resolved = format!("{resolved}/{}", escape_token(&token));
+  }
+
+  Ok(current)
+}
+
+/// Array indices are plain decimal numbers without leading zeros.
+fn parse_index(token: &str) -> Option<usize> {
+  if token.is_empty() || (token.len() > 1 && token.starts_with('0')) {
+    return None;
+  }
+  if !token.bytes().all(|b| b.is_ascii_digit()) {
+    return None;
+  }
+
+  token.parse().ok()
+}
+
+/// Percent-decode a URI fragment.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::pointer::decode_fragment;
+/// assert_eq!(decode_fragment("/$defs/Street%20Address").unwrap(), "/$defs/Street Address");
+/// assert!(decode_fragment("/%FF").is_err());
+/// ```
+pub fn decode_fragment(fragment: &str) -> Result<String, PointerError> {
+  percent_decode_str(fragment)
+    .decode_utf8()
+    .map(|s| s.into_owned())
+    .map_err(|_| PointerError::BadEncoding(fragment.to_owned()))
+}
+
+/// Characters that can't appear as-is in a URI fragment.
+const FRAGMENT_ESCAPES: &AsciiSet = &CONTROLS
+  .add(b' ')
+  .add(b'"')
+  .add(b'#')
+  .add(b'%')
+  .add(b'<')
+  .add(b'>')
+  .add(b'[')
+  .add(b']')
+  .add(b'^')
+  .add(b'`')
+  .add(b'{')
+  .add(b'|')
+  .add(b'}');
+
+/// Percent-encode a JSON Pointer for use as a URI fragment; the inverse of
+/// [`decode_fragment`].
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::pointer::{decode_fragment, encode_fragment};
+/// assert_eq!(encode_fragment("/properties/Street Address"), "/properties/Street%20Address");
+/// assert_eq!(decode_fragment(&encode_fragment("/a%b/c#d")).unwrap(), "/a%b/c#d");
+/// ```
+pub fn encode_fragment(pointer: &str) -> String {
+  utf8_percent_encode(pointer, FRAGMENT_ESCAPES).to_string()
+}
+
+/// Evaluate a URI fragment holding a JSON Pointer against a document, decoding it first.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::pointer::resolve_fragment;
+/// let schema = serde_json::json!({"$defs": {"a/b": {"properties": {"c%d": true}}}});
+/// let url = url::Url::parse("https://foo.com/x.json#/$defs/a~1b/properties/c%25d").unwrap();
+/// assert_eq!(resolve_fragment(&schema, url.fragment().unwrap()).unwrap(), true);
+/// ```
+pub fn resolve_fragment<'a>(
+  node: &'a JsonValue,
+  fragment: &str,
+) -> Result<&'a JsonValue, PointerError> {
+  resolve_pointer(node, &decode_fragment(fragment)?)
+}
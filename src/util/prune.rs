@@ -0,0 +1,570 @@
+use serde_json::Value as JsonValue;
+use std::collections::{BTreeSet, HashMap};
+use url::Url;
+
+use crate::bundler::{normalize_uri, SchemaId, SchemaMap};
+use crate::dialect::Dialect;
+use crate::pointer::{decode_fragment, escape_token, unescape_token};
+use crate::resolver::{
+  find_anchors, find_embedded_resources, find_refs, keyword_children, Anchors, ResolvedRef,
+};
+
+/// The base URI references are resolved against in a document without an `$id`.
+const UNIDENTIFIED_BASE: &str = "bundle-schema:/document";
+
+/// A document with its unreferenced definitions removed.
+#[derive(Debug)]
+pub struct Pruned {
+  pub schema: JsonValue,
+  /// JSON Pointers to the definitions that were removed. Those nested in another removed
+  /// definition aren't listed separately.
+  pub dropped: Vec<String>,
+}
+
+/// Remove every `$defs` and `definitions` entry in a document, written in `dialect` or
+/// the one its `$schema` names, that can't be reached from its root through a chain of
+/// references.
+///
+/// References are followed within the document only, including into any resources
+/// embedded in it, by pointer, anchor or `$id`. Keeping part of a definition keeps all
+/// of it, along with whatever it refers to. A definitions keyword left empty is removed
+/// too.
+///
+/// A definition declaring a `$dynamicAnchor`, or `"$recursiveAnchor": true`, can be
+/// reached through the dynamic scope instead, so it's kept whenever a reachable
+/// `$dynamicRef` or `$recursiveRef` could land on it. Since a schema this one refers to
+/// could do the same, all of them are kept once a reachable reference leaves the
+/// document.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::dialect::Dialect;
+/// # use bundle_schema::prune::prune;
+/// let schema = serde_json::json!({
+///   "$id": "https://foo.com/order.json",
+///   "properties": {"total": {"$ref": "#/$defs/money"}},
+///   "$defs": {
+///     "money": {"$ref": "#/$defs/amount"},
+///     "amount": {"type": "number"},
+///     "unused": {"$ref": "#/$defs/helper"},
+///     "helper": {"type": "string"}
+///   },
+///   "definitions": {"legacy": {"type": "null"}}
+/// });
+///
+/// let pruned = prune(&schema, Dialect::Draft2020_12);
+/// assert_eq!(pruned.dropped, ["/$defs/helper", "/$defs/unused", "/definitions/legacy"]);
+/// assert_eq!(
+///   pruned.schema,
+///   serde_json::json!({
+///     "$id": "https://foo.com/order.json",
+///     "properties": {"total": {"$ref": "#/$defs/money"}},
+///     "$defs": {"money": {"$ref": "#/$defs/amount"}, "amount": {"type": "number"}}
+///   })
+/// );
+/// ```
+pub fn prune(schema: &JsonValue, dialect: Dialect) -> Pruned {
+  let dialect = Dialect::detect(schema, dialect);
+  let base = match SchemaId::from_json_value_as(schema, dialect) {
+    Ok(id) => id.full_id,
+    Err(_) => Url::parse(UNIDENTIFIED_BASE).expect("the placeholder base URI is valid"),
+  };
+
+  let mut definitions = Definitions::new(schema, dialect, Some(&base));
+  loop {
+    let followed = definitions.follow();
+    if followed.is_empty() {
+      break;
+    }
+    for found in followed {
+      if let Some(name) = dynamic_name(&found) {
+        definitions.reach_dynamic(&name);
+      }
+      if !definitions.reach(&found.target) {
+        definitions.reach_all_dynamic();
+      }
+    }
+  }
+
+  Pruned {
+    schema: definitions.prune(schema),
+    dropped: definitions.dropped(),
+  }
+}
+
+/// The name a `$dynamicRef` looks for in the dynamic scope, or `None` for a
+/// `$recursiveRef`, which looks for `"$recursiveAnchor": true`.
+type DynamicName = Option<String>;
+
+/// The definitions and references in a single document, and how much of it has been
+/// reached so far.
+#[derive(Debug)]
+pub(crate) struct Definitions {
+  /// The document's URI and location of each resource in it, with its anchors.
+  resources: Vec<(Url, String, Anchors)>,
+  /// The location of every `$defs` and `definitions` entry.
+  definitions: Vec<String>,
+  /// The location of every subschema declaring a dynamic anchor.
+  dynamic_anchors: Vec<(DynamicName, String)>,
+  refs: Vec<ResolvedRef>,
+  followed: Vec<bool>,
+  /// The definitions reached so far.
+  live: BTreeSet<String>,
+}
+
+impl Definitions {
+  /// Index a document whose own URI is `base`.
+  pub(crate) fn new(schema: &JsonValue, dialect: Dialect, base: Option<&Url>) -> Self {
+    let mut resources = Vec::new();
+    if let Some(base) = base {
+      resources.push((
+        normalize_uri(base),
+        String::new(),
+        find_anchors(schema, dialect),
+      ));
+      for resource in find_embedded_resources(schema, base, dialect) {
+        if let Some(node) = schema.pointer(&resource.location) {
+          let anchors = find_anchors(node, resource.dialect);
+          resources.push((normalize_uri(&resource.id), resource.location, anchors));
+        }
+      }
+    }
+
+    let mut definitions = Vec::new();
+    let mut dynamic_anchors = Vec::new();
+    index(
+      schema,
+      String::new(),
+      &mut definitions,
+      &mut dynamic_anchors,
+    );
+    let refs = find_refs(schema, base, dialect);
+
+    Definitions {
+      resources,
+      definitions,
+      dynamic_anchors,
+      followed: vec![false; refs.len()],
+      refs,
+      live: BTreeSet::new(),
+    }
+  }
+
+  /// Whether `uri`, ignoring its fragment, is the document or a resource inside it.
+  pub(crate) fn holds(&self, uri: &Url) -> bool {
+    let mut resource = uri.clone();
+    resource.set_fragment(None);
+    let resource = normalize_uri(&resource);
+    self.resources.iter().any(|(id, _, _)| *id == resource)
+  }
+
+  /// Mark every definition as reached.
+  pub(crate) fn reach_everything(&mut self) {
+    self.live.extend(self.definitions.iter().cloned());
+  }
+
+  /// Mark the definitions holding a reference's target as reached, returning whether
+  /// the target is in this document at all.
+  pub(crate) fn reach(&mut self, target: &Url) -> bool {
+    match self.locate(target) {
+      Some(location) => {
+        self.mark(&location);
+        true
+      }
+      None => self.holds(target),
+    }
+  }
+
+  /// Mark the definitions holding each dynamic anchor called `name` as reached.
+  pub(crate) fn reach_dynamic(&mut self, name: &DynamicName) {
+    let locations: Vec<String> = self
+      .dynamic_anchors
+      .iter()
+      .filter(|(anchor, _)| anchor == name)
+      .map(|(_, location)| location.clone())
+      .collect();
+    for location in locations {
+      self.mark(&location);
+    }
+  }
+
+  fn reach_all_dynamic(&mut self) {
+    let locations: Vec<String> = self
+      .dynamic_anchors
+      .iter()
+      .map(|(_, location)| location.clone())
+      .collect();
+    for location in locations {
+      self.mark(&location);
+    }
+  }
+
+  fn mark(&mut self, location: &str) {
+    for definition in &self.definitions {
+      if encloses(definition, location) {
+        self.live.insert(definition.clone());
+      }
+    }
+  }
+
+  /// Whether `location` is outside every definition that hasn't been reached.
+  fn is_live(&self, location: &str) -> bool {
+    !self
+      .definitions
+      .iter()
+      .any(|d| encloses(d, location) && !self.live.contains(d))
+  }
+
+  /// Follow the references reached since last time, returning them.
+  pub(crate) fn follow(&mut self) -> Vec<ResolvedRef> {
+    let mut followed = Vec::new();
+    for (idx, found) in self.refs.iter().enumerate() {
+      if !self.followed[idx] && self.is_live(&found.location) {
+        self.followed[idx] = true;
+        followed.push(found.clone());
+      }
+    }
+    followed
+  }
+
+  /// Every reference followed so far, in document order.
+  pub(crate) fn followed(&self) -> Vec<ResolvedRef> {
+    self
+      .refs
+      .iter()
+      .zip(&self.followed)
+      .filter(|(_, followed)| **followed)
+      .map(|(found, _)| found.clone())
+      .collect()
+  }
+
+  /// The definitions that haven't been reached, leaving out those nested in another.
+  pub(crate) fn dropped(&self) -> Vec<String> {
+    let dead: Vec<&String> = self
+      .definitions
+      .iter()
+      .filter(|d| !self.live.contains(*d))
+      .collect();
+    dead
+      .iter()
+      .filter(|d| !dead.iter().any(|other| other != *d && encloses(other, d)))
+      .map(|d| d.to_string())
+      .collect()
+  }
+
+  /// A copy of the document without the definitions that haven't been reached.
+  pub(crate) fn prune(&self, schema: &JsonValue) -> JsonValue {
+    let mut pruned = schema.clone();
+    let mut emptied = BTreeSet::new();
+    for location in self.dropped() {
+      let Some((container, name)) = location.rsplit_once('/') else {
+        continue;
+      };
+      if let Some(defs) = pruned
+        .pointer_mut(container)
+        .and_then(JsonValue::as_object_mut)
+      {
+        defs.remove(&unescape_token(name));
+        if defs.is_empty() {
+          emptied.insert(container.to_owned());
+        }
+      }
+    }
+    for location in emptied {
+      let Some((parent, keyword)) = location.rsplit_once('/') else {
+        continue;
+      };
+      if let Some(parent) = pruned
+        .pointer_mut(parent)
+        .and_then(JsonValue::as_object_mut)
+      {
+        parent.remove(&unescape_token(keyword));
+      }
+    }
+    pruned
+  }
+
+  /// Where in the document a reference's target is, if it's in there.
+  fn locate(&self, target: &Url) -> Option<String> {
+    let mut resource = target.clone();
+    resource.set_fragment(None);
+    let resource = normalize_uri(&resource);
+    let (_, location, anchors) = self.resources.iter().find(|(id, _, _)| *id == resource)?;
+
+    match target.fragment() {
+      None | Some("") => Some(location.clone()),
+      Some(fragment) => {
+        let fragment = decode_fragment(fragment).ok()?;
+        match fragment.starts_with('/') {
+          true => Some(format!("{location}{fragment}")),
+          false => Some(format!("{location}{}", anchors.plain.get(&fragment)?)),
+        }
+      }
+    }
+  }
+}
+
+/// The references reachable from a root schema, across the registered documents.
+#[derive(Debug)]
+pub(crate) struct Reachable {
+  /// The root's URI, or a stand-in for a root without one.
+  root: Url,
+  /// What's been reached in each document, by its URI.
+  documents: HashMap<Url, Definitions>,
+  /// The targets of reachable references that aren't registered, without fragments.
+  pub(crate) unregistered: BTreeSet<Url>,
+}
+
+impl Reachable {
+  /// The reachable references in the document at `uri`, or the root for `None`.
+  pub(crate) fn refs(&self, uri: Option<&Url>) -> Vec<ResolvedRef> {
+    self
+      .documents
+      .get(uri.unwrap_or(&self.root))
+      .map(Definitions::followed)
+      .unwrap_or_default()
+  }
+
+  /// The document at `uri` without the definitions that can't be reached, along with
+  /// where they were.
+  pub(crate) fn prune(&self, uri: &Url, schema: &JsonValue) -> Pruned {
+    match self.documents.get(uri) {
+      Some(definitions) => Pruned {
+        schema: definitions.prune(schema),
+        dropped: definitions.dropped(),
+      },
+      None => Pruned {
+        schema: schema.clone(),
+        dropped: Vec::new(),
+      },
+    }
+  }
+}
+
+/// Follow references from `root`, and every definition in it, through the documents
+/// registered in `schemas`, one definition at a time.
+pub(crate) fn find_reachable(root: &JsonValue, schemas: &SchemaMap) -> Reachable {
+  let root_dialect = Dialect::detect(root, schemas.default_dialect);
+  let root_id = SchemaId::from_json_value_as(root, root_dialect)
+    .ok()
+    .map(|id| id.full_id);
+  let root_uri = match &root_id {
+    Some(id) => id.clone(),
+    None => Url::parse(UNIDENTIFIED_BASE).expect("the placeholder base URI is valid"),
+  };
+
+  let mut root_definitions = Definitions::new(root, root_dialect, root_id.as_ref());
+  root_definitions.reach_everything();
+  let mut documents = HashMap::from([(root_uri.clone(), root_definitions)]);
+  let mut dynamic_names: BTreeSet<DynamicName> = BTreeSet::new();
+  let mut unregistered = BTreeSet::new();
+
+  let mut pending = vec![root_uri.clone()];
+  while let Some(uri) = pending.pop() {
+    let followed = match documents.get_mut(&uri) {
+      Some(definitions) => definitions.follow(),
+      None => continue,
+    };
+
+    for found in followed {
+      if let Some(name) = dynamic_name(&found) {
+        if dynamic_names.insert(name.clone()) {
+          for (uri, definitions) in documents.iter_mut() {
+            definitions.reach_dynamic(&name);
+            pending.push(uri.clone());
+          }
+        }
+      }
+
+      let document = match documents[&root_uri].holds(&found.target) {
+        true => root_uri.clone(),
+        false => {
+          let mut resource = found.target.clone();
+          resource.set_fragment(None);
+          match schemas.get_item(&resource) {
+            Some(item) => item
+              .parent
+              .clone()
+              .unwrap_or_else(|| item.id.full_id.clone()),
+            None => {
+              unregistered.insert(resource);
+              continue;
+            }
+          }
+        }
+      };
+      if !documents.contains_key(&document) {
+        let Some(item) = schemas.get_item(&document) else {
+          continue;
+        };
+        let mut definitions = Definitions::new(&item.node, item.dialect, Some(&item.id.full_id));
+        for name in &dynamic_names {
+          definitions.reach_dynamic(name);
+        }
+        documents.insert(document.clone(), definitions);
+      }
+      if let Some(definitions) = documents.get_mut(&document) {
+        definitions.reach(&found.target);
+      }
+      pending.push(document);
+    }
+  }
+
+  Reachable {
+    root: root_uri,
+    documents,
+    unregistered,
+  }
+}
+
+/// What a `$dynamicRef` or `$recursiveRef` looks for in the dynamic scope, if anything.
+fn dynamic_name(found: &ResolvedRef) -> Option<DynamicName> {
+  if !found.dynamic {
+    return None;
+  }
+  let fragment = decode_fragment(found.target.fragment().unwrap_or_default()).ok()?;
+  match fragment.as_str() {
+    "" => Some(None),
+    pointer if pointer.starts_with('/') => None,
+    name => Some(Some(name.to_owned())),
+  }
+}
+
+/// Whether the JSON Pointer `location` is `outer` or points beneath it.
+fn encloses(outer: &str, location: &str) -> bool {
+  location
+    .strip_prefix(outer)
+    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Collect the location of every `$defs` and `definitions` entry, and of every
+/// subschema declaring a dynamic anchor, beneath the subschema `node`.
+fn index(
+  node: &JsonValue,
+  location: String,
+  definitions: &mut Vec<String>,
+  dynamic_anchors: &mut Vec<(DynamicName, String)>,
+) {
+  match node {
+    JsonValue::Object(map) => {
+      if let Some(name) = map.get("$dynamicAnchor").and_then(JsonValue::as_str) {
+        dynamic_anchors.push((Some(name.to_owned()), location.clone()));
+      }
+      if map.get("$recursiveAnchor") == Some(&JsonValue::Bool(true)) {
+        dynamic_anchors.push((None, location.clone()));
+      }
+      for (key, value) in map {
+        if let ("$defs" | "definitions", JsonValue::Object(defs)) = (key.as_str(), value) {
+          for (name, definition) in defs {
+            let entry = format!("{location}/{}/{}", escape_token(key), escape_token(name));
+            definitions.push(entry.clone());
+            index(definition, entry, definitions, dynamic_anchors);
+          }
+          continue;
+        }
+        for (child, child_location) in keyword_children(key, value, &location) {
+          index(child, child_location, definitions, dynamic_anchors);
+        }
+      }
+    }
+    JsonValue::Array(items) => {
+      for (idx, item) in items.iter().enumerate() {
+        index(
+          item,
+          format!("{location}/{idx}"),
+          definitions,
+          dynamic_anchors,
+        );
+      }
+    }
+    _ => {}
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_prune_follows_anchors_and_embedded_resources() {
+    let schema = json!({
+      "$id": "https://foo.com/bundle.json",
+      "$ref": "https://foo.com/common.json#money",
+      "$defs": {
+        "https://foo.com/common.json": {
+          "$id": "https://foo.com/common.json",
+          "$defs": {
+            "money": {"$anchor": "money", "$ref": "#/$defs/amount"},
+            "amount": {"type": "number"},
+            "name": {"type": "string"}
+          }
+        },
+        "https://foo.com/unused.json": {
+          "$id": "https://foo.com/unused.json",
+          "$defs": {"x": {"$ref": "#/$defs/y"}, "y": {}}
+        }
+      }
+    });
+
+    let pruned = prune(&schema, Dialect::Draft2020_12);
+    assert_eq!(
+      pruned.dropped,
+      [
+        "/$defs/https:~1~1foo.com~1common.json/$defs/name",
+        "/$defs/https:~1~1foo.com~1unused.json",
+      ]
+    );
+    let common = &pruned.schema["$defs"]["https://foo.com/common.json"]["$defs"];
+    assert_eq!(common.as_object().unwrap().len(), 2);
+  }
+
+  #[test]
+  fn test_prune_only_treats_keywords_as_definitions() {
+    let schema = json!({
+      "properties": {
+        "definitions": {"type": "array", "items": {"type": "string"}},
+        "default": {"$defs": {"unused": {}}}
+      }
+    });
+
+    let pruned = prune(&schema, Dialect::Draft2020_12);
+    assert_eq!(pruned.dropped, ["/properties/default/$defs/unused"]);
+    assert_eq!(
+      pruned.schema,
+      json!({
+        "properties": {
+          "definitions": {"type": "array", "items": {"type": "string"}},
+          "default": {}
+        }
+      })
+    );
+  }
+
+  #[test]
+  fn test_prune_keeps_dynamic_anchors_a_reachable_ref_could_land_on() {
+    let list = json!({
+      "$id": "https://foo.com/list.json",
+      "items": {"$dynamicRef": "#item"},
+      "$defs": {
+        "item": {"$dynamicAnchor": "item"},
+        "other": {"$dynamicAnchor": "other"}
+      }
+    });
+    let pruned = prune(&list, Dialect::Draft2020_12);
+    assert_eq!(pruned.dropped, ["/$defs/other"]);
+
+    // Another schema could look for any of them, once a reference leaves the document.
+    let strlist = json!({
+      "$id": "https://foo.com/strlist.json",
+      "$ref": "list.json",
+      "$defs": {
+        "item": {"$dynamicAnchor": "item", "type": "string"},
+        "unused": {"type": "null"}
+      }
+    });
+    let pruned = prune(&strlist, Dialect::Draft2020_12);
+    assert_eq!(pruned.dropped, ["/$defs/unused"]);
+  }
+}
@@ -0,0 +1,409 @@
+use log::warn;
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use url::Url;
+
+use crate::dialect::Dialect;
+use crate::pointer::escape_token;
+
+/// Keywords whose values are plain data rather than subschemas, so any `$id` or
+/// `$ref` inside them means nothing.
+pub(crate) const DATA_KEYWORDS: [&str; 4] = ["const", "default", "enum", "examples"];
+
+/// Keywords whose values map names onto subschemas. Their keys are names, not keywords.
+pub(crate) const NAMED_SUBSCHEMA_KEYWORDS: [&str; 6] = [
+  "$defs",
+  "definitions",
+  "dependencies",
+  "dependentSchemas",
+  "patternProperties",
+  "properties",
+];
+
+/// A `$ref`, `$dynamicRef` or `$recursiveRef` found while walking a schema, along
+/// with the absolute URI it resolves to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedRef {
+  /// JSON Pointer to the object holding the reference, relative to the node that was walked.
+  pub location: String,
+  /// The reference exactly as it was written.
+  pub reference: String,
+  /// Whether this came from `$dynamicRef` or `$recursiveRef` rather than `$ref`.
+  pub dynamic: bool,
+  /// The reference resolved against the nearest enclosing `$id`.
+  pub target: Url,
+}
+
+/// Resolve a reference against a base URI, following RFC 3986.
+///
+/// Without a base, only absolute references can be resolved.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::resolver::resolve_reference;
+/// # use url::Url;
+/// let base = Url::parse("https://foo.com/schemas/api/order.json").unwrap();
+/// let target = resolve_reference(Some(&base), "../common/address.json#/properties/zip").unwrap();
+/// assert_eq!(target.as_str(), "https://foo.com/schemas/common/address.json#/properties/zip");
+///
+/// assert!(resolve_reference(None, "address.json").is_err());
+/// ```
+pub fn resolve_reference(base: Option<&Url>, reference: &str) -> Result<Url, url::ParseError> {
+  match base {
+    Some(b) => b.join(reference),
+    None => Url::parse(reference),
+  }
+}
+
+/// The identifier of a subschema that changes the base URI, if it has one.
+///
+/// Up to draft-07, an identifier that's only a fragment names an anchor instead, and
+/// one beside a `$ref` is ignored.
+pub(crate) fn base_identifier(
+  map: &serde_json::Map<String, JsonValue>,
+  dialect: Dialect,
+) -> Option<&str> {
+  let id = map.get(dialect.id_keyword())?.as_str()?;
+  if dialect.has_fragment_ids() && id.starts_with('#') {
+    return None;
+  }
+  if dialect.ignores_ref_siblings() && map.get("$ref").is_some_and(JsonValue::is_string) {
+    return None;
+  }
+  Some(id)
+}
+
+/// The values to walk beneath the keyword `key` of the subschema at `location`, each with
+/// its own location.
+///
+/// Data keywords have none. The entries of a keyword naming subschemas, like `properties`,
+/// are subschemas themselves, so a property called `default` is still walked.
+pub(crate) fn keyword_children<'a>(
+  key: &str,
+  value: &'a JsonValue,
+  location: &str,
+) -> Vec<(&'a JsonValue, String)> {
+  if DATA_KEYWORDS.contains(&key) {
+    return Vec::new();
+  }
+  let location = format!("{location}/{}", escape_token(key));
+  match value {
+    JsonValue::Object(named) if NAMED_SUBSCHEMA_KEYWORDS.contains(&key) => named
+      .iter()
+      .map(|(name, subschema)| (subschema, format!("{location}/{}", escape_token(name))))
+      .collect(),
+    _ => vec![(value, location)],
+  }
+}
+
+/// Whether a subschema's keywords, besides its `$ref`, are ignored.
+fn only_ref_applies(map: &serde_json::Map<String, JsonValue>, dialect: Dialect) -> bool {
+  dialect.ignores_ref_siblings() && map.get("$ref").is_some_and(JsonValue::is_string)
+}
+
+/// Find every reference beneath `node`, resolving each one against the nearest
+/// enclosing identifier.
+///
+/// `base` is the URI of the document being walked, and `dialect` its dialect, which
+/// decides the identifier and reference keywords. Subschemas declaring their own
+/// identifier change the base for themselves and everything beneath them, and ones
+/// declaring their own `$schema` change the dialect. References that can't be
+/// resolved are logged and skipped.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::dialect::Dialect;
+/// # use bundle_schema::resolver::find_refs;
+/// # use url::Url;
+/// let schema = serde_json::json!({
+///   "$id": "https://foo.com/schemas/api/order.json",
+///   "properties": {
+///     "shipTo": {"$ref": "../common/address.json"},
+///     "children": {"items": {"$dynamicRef": "#node"}},
+///     "payment": {
+///       "$id": "https://foo.com/schemas/billing/payment.json",
+///       "properties": {"total": {"$ref": "money.json#/$defs/Money"}}
+///     }
+///   }
+/// });
+/// let base = Url::parse("https://foo.com/schemas/api/order.json").unwrap();
+/// let refs = find_refs(&schema, Some(&base), Dialect::Draft2020_12);
+///
+/// assert_eq!(refs.len(), 3);
+/// assert!(refs[0].dynamic);
+/// assert_eq!(refs[0].target.as_str(), "https://foo.com/schemas/api/order.json#node");
+/// assert_eq!(refs[1].location, "/properties/payment/properties/total");
+/// assert_eq!(refs[1].target.as_str(), "https://foo.com/schemas/billing/money.json#/$defs/Money");
+/// assert_eq!(refs[2].location, "/properties/shipTo");
+/// assert_eq!(refs[2].target.as_str(), "https://foo.com/schemas/common/address.json");
+/// ```
+///
+/// Up to draft-07, everything beside a `$ref` is ignored, and draft-04 uses `id`:
+///
+/// ```rust
+/// # use bundle_schema::dialect::Dialect;
+/// # use bundle_schema::resolver::find_refs;
+/// # use url::Url;
+/// let schema = serde_json::json!({
+///   "id": "https://foo.com/schemas/order.json",
+///   "properties": {
+///     "shipTo": {"$ref": "address.json", "id": "elsewhere/", "properties": {"x": {"$ref": "ignored.json"}}},
+///     "payment": {"id": "billing/payment.json", "$ref": "#/definitions/card"}
+///   }
+/// });
+/// let base = Url::parse("https://foo.com/schemas/order.json").unwrap();
+/// let refs = find_refs(&schema, Some(&base), Dialect::Draft04);
+///
+/// assert_eq!(refs.len(), 2);
+/// assert_eq!(refs[0].target.as_str(), "https://foo.com/schemas/order.json#/definitions/card");
+/// assert_eq!(refs[1].target.as_str(), "https://foo.com/schemas/address.json");
+/// ```
+pub fn find_refs(node: &JsonValue, base: Option<&Url>, dialect: Dialect) -> Vec<ResolvedRef> {
+  let mut found = Vec::new();
+  walk(node, base, dialect, String::new(), &mut found);
+  found
+}
+
+fn walk(
+  node: &JsonValue,
+  base: Option<&Url>,
+  dialect: Dialect,
+  location: String,
+  found: &mut Vec<ResolvedRef>,
+) {
+  match node {
+    JsonValue::Object(map) => {
+      let dialect = dialect.within(node);
+      let own_base = match base_identifier(map, dialect) {
+        Some(id) => match resolve_reference(base, id) {
+          Ok(u) => Some(u),
+          Err(e) => {
+            warn!("Unable to resolve $id «{id}» at «{location}»: {e}");
+            base.cloned()
+          }
+        },
+        None => base.cloned(),
+      };
+
+      let only_ref = only_ref_applies(map, dialect);
+      for (key, value) in map {
+        let dynamic = Some(key.as_str()) == dialect.dynamic_ref_keyword();
+        if key == "$ref" || dynamic {
+          if let Some(reference) = value.as_str() {
+            match resolve_reference(own_base.as_ref(), reference) {
+              Ok(target) => found.push(ResolvedRef {
+                location: location.clone(),
+                reference: reference.to_owned(),
+                dynamic,
+                target,
+              }),
+              Err(e) => warn!("Unable to resolve {key} «{reference}» at «{location}»: {e}"),
+            }
+            continue;
+          }
+        }
+        if only_ref {
+          continue;
+        }
+        for (child, child_location) in keyword_children(key, value, &location) {
+          walk(child, own_base.as_ref(), dialect, child_location, found);
+        }
+      }
+    }
+    JsonValue::Array(items) => {
+      for (idx, item) in items.iter().enumerate() {
+        walk(item, base, dialect, format!("{location}/{idx}"), found);
+      }
+    }
+    _ => {}
+  }
+}
+
+/// A subschema that declares its own `$id`, making it a schema resource of its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmbeddedResource {
+  /// The subschema's `$id`, resolved against the enclosing base URI.
+  pub id: Url,
+  /// JSON Pointer to the subschema, relative to the node that was walked.
+  pub location: String,
+  /// The subschema's dialect.
+  pub dialect: Dialect,
+}
+
+/// Find every subschema beneath `node` that declares its own `$id`.
+///
+/// `base` is the URI of the document being walked, whose own `$id` isn't included.
+/// Resources nested inside other embedded resources are found as well, each resolved
+/// against its nearest enclosing `$id`.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::dialect::Dialect;
+/// # use bundle_schema::resolver::find_embedded_resources;
+/// # use url::Url;
+/// let bundle = serde_json::json!({
+///   "$id": "https://foo.com/schemas/order.json",
+///   "$defs": {
+///     "address": {
+///       "$id": "common/address.json",
+///       "$defs": {"geo": {"$id": "geo.json"}}
+///     }
+///   }
+/// });
+/// let base = Url::parse("https://foo.com/schemas/order.json").unwrap();
+/// let found = find_embedded_resources(&bundle, &base, Dialect::Draft2020_12);
+///
+/// assert_eq!(found.len(), 2);
+/// assert_eq!(found[0].id.as_str(), "https://foo.com/schemas/common/address.json");
+/// assert_eq!(found[0].location, "/$defs/address");
+/// assert_eq!(found[1].id.as_str(), "https://foo.com/schemas/common/geo.json");
+/// assert_eq!(found[1].location, "/$defs/address/$defs/geo");
+/// ```
+pub fn find_embedded_resources(
+  node: &JsonValue,
+  base: &Url,
+  dialect: Dialect,
+) -> Vec<EmbeddedResource> {
+  let mut found = Vec::new();
+  walk_resources(node, base, dialect, String::new(), &mut found);
+  found
+}
+
+fn walk_resources(
+  node: &JsonValue,
+  base: &Url,
+  dialect: Dialect,
+  location: String,
+  found: &mut Vec<EmbeddedResource>,
+) {
+  match node {
+    JsonValue::Object(map) => {
+      let dialect = dialect.within(node);
+      let mut own_base = base.clone();
+      if !location.is_empty() {
+        if let Some(id) = base_identifier(map, dialect) {
+          match base.join(id) {
+            Ok(u) => {
+              found.push(EmbeddedResource {
+                id: u.clone(),
+                location: location.clone(),
+                dialect,
+              });
+              own_base = u;
+            }
+            Err(e) => warn!("Unable to resolve $id «{id}» at «{location}»: {e}"),
+          }
+        }
+      }
+      if only_ref_applies(map, dialect) {
+        return;
+      }
+
+      for (key, value) in map {
+        for (child, child_location) in keyword_children(key, value, &location) {
+          walk_resources(child, &own_base, dialect, child_location, found);
+        }
+      }
+    }
+    JsonValue::Array(items) => {
+      for (idx, item) in items.iter().enumerate() {
+        walk_resources(item, base, dialect, format!("{location}/{idx}"), found);
+      }
+    }
+    _ => {}
+  }
+}
+
+/// Plain-name fragments declared within a single schema resource.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Anchors {
+  /// Every `$anchor` and `$dynamicAnchor` name, mapped to the JSON Pointer of the
+  /// subschema declaring it.
+  pub plain: HashMap<String, String>,
+  /// Only the `$dynamicAnchor` names, mapped the same way.
+  pub dynamic: HashMap<String, String>,
+}
+
+/// Index the anchors declared by a schema resource written in `dialect`.
+///
+/// Subschemas declaring their own `$id` are separate resources, so their anchors
+/// aren't included. A `$dynamicAnchor` also acts as a plain-name fragment, so it's
+/// recorded in both maps. Up to draft-07, anchors are declared by identifiers that
+/// are only a fragment, like `"$id": "#street"`.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::dialect::Dialect;
+/// # use bundle_schema::resolver::find_anchors;
+/// let schema = serde_json::json!({
+///   "$id": "https://foo.com/address.json",
+///   "$dynamicAnchor": "node",
+///   "properties": {
+///     "street": {"$anchor": "street", "type": "string"},
+///     "geo": {"$id": "geo.json", "$anchor": "point"}
+///   }
+/// });
+/// let anchors = find_anchors(&schema, Dialect::Draft2020_12);
+///
+/// assert_eq!(anchors.plain["street"], "/properties/street");
+/// assert_eq!(anchors.plain["node"], "");
+/// assert_eq!(anchors.dynamic["node"], "");
+/// assert!(!anchors.plain.contains_key("point"));
+///
+/// let schema = serde_json::json!({
+///   "$id": "https://foo.com/address.json",
+///   "properties": {"street": {"$id": "#street", "type": "string"}}
+/// });
+/// let anchors = find_anchors(&schema, Dialect::Draft07);
+/// assert_eq!(anchors.plain["street"], "/properties/street");
+/// ```
+pub fn find_anchors(resource: &JsonValue, dialect: Dialect) -> Anchors {
+  let mut anchors = Anchors::default();
+  walk_anchors(resource, dialect, String::new(), &mut anchors);
+  anchors
+}
+
+fn walk_anchors(node: &JsonValue, dialect: Dialect, location: String, anchors: &mut Anchors) {
+  match node {
+    JsonValue::Object(map) => {
+      let dialect = dialect.within(node);
+      if !location.is_empty() && base_identifier(map, dialect).is_some() {
+        return;
+      }
+      if only_ref_applies(map, dialect) {
+        return;
+      }
+
+      if dialect.has_fragment_ids() {
+        let id = map.get(dialect.id_keyword()).and_then(JsonValue::as_str);
+        if let Some(name) = id.and_then(|id| id.strip_prefix('#')) {
+          anchors.plain.insert(name.to_owned(), location.clone());
+        }
+      } else {
+        if let Some(name) = map.get("$anchor").and_then(JsonValue::as_str) {
+          anchors.plain.insert(name.to_owned(), location.clone());
+        }
+        if let Some(name) = map.get("$dynamicAnchor").and_then(JsonValue::as_str) {
+          anchors.plain.insert(name.to_owned(), location.clone());
+          anchors.dynamic.insert(name.to_owned(), location.clone());
+        }
+      }
+
+      for (key, value) in map {
+        for (child, child_location) in keyword_children(key, value, &location) {
+          walk_anchors(child, dialect, child_location, anchors);
+        }
+      }
+    }
+    JsonValue::Array(items) => {
+      for (idx, item) in items.iter().enumerate() {
+        walk_anchors(item, dialect, format!("{location}/{idx}"), anchors);
+      }
+    }
+    _ => {}
+  }
+}
@@ -0,0 +1,402 @@
+use log::debug;
+use percent_encoding::percent_decode_str;
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use url::Url;
+
+use crate::bundler::normalize_uri;
+use crate::error::BundleError;
+use crate::inputs::{parse_document, InputDocument};
+
+/// Somewhere [`SchemaMap`](crate::bundler::SchemaMap) can look for a schema that a
+/// reference points at but that hasn't been registered.
+///
+/// Implement it to load schemas from other places, like an internal artifact store.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::error::BundleError;
+/// # use bundle_schema::inputs::InputDocument;
+/// # use bundle_schema::retriever::SchemaRetriever;
+/// #[derive(Debug)]
+/// struct Everything;
+///
+/// impl SchemaRetriever for Everything {
+///   fn retrieve(&self, uri: &url::Url) -> Result<Option<InputDocument>, BundleError> {
+///     Ok(Some(InputDocument {
+///       source: uri.to_string(),
+///       schema: serde_json::json!(true),
+///       file: None,
+///     }))
+///   }
+/// }
+/// ```
+pub trait SchemaRetriever: Debug {
+  /// Load the schema at `uri`, which never has a fragment.
+  ///
+  /// Returns `None` when this retriever doesn't know about `uri`, so that the next one
+  /// can be tried. A schema without an `$id` is given `uri` as its `$id`.
+  fn retrieve(&self, uri: &Url) -> Result<Option<InputDocument>, BundleError>;
+}
+
+/// Maps every URI starting with a prefix onto a file beneath a local directory, so
+/// that `https://schemas.example.com/types/money.json` can be read from
+/// `./schemas/types/money.json`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UriMapping {
+  pub prefix: String,
+  pub dir: PathBuf,
+}
+
+impl FromStr for UriMapping {
+  type Err = String;
+
+  /// Parse a mapping written as `PREFIX=DIR`.
+  ///
+  /// # Examples
+  ///
+  /// ```rust
+  /// # use bundle_schema::retriever::UriMapping;
+  /// let mapping: UriMapping = "https://schemas.example.com/=./schemas/".parse().unwrap();
+  /// assert_eq!(mapping.prefix, "https://schemas.example.com/");
+  /// assert_eq!(mapping.dir, std::path::Path::new("./schemas/"));
+  ///
+  /// assert!("https://schemas.example.com/".parse::<UriMapping>().is_err());
+  /// ```
+  fn from_str(mapping: &str) -> Result<Self, Self::Err> {
+    let Some((prefix, dir)) = mapping.split_once('=') else {
+      return Err(format!("«{mapping}» should look like `PREFIX=DIR`"));
+    };
+    let prefix =
+      Url::parse(prefix).map_err(|e| format!("«{prefix}» isn't an absolute URI: {e}"))?;
+
+    Ok(UriMapping {
+      prefix: prefix.into(),
+      dir: PathBuf::from(dir),
+    })
+  }
+}
+
+impl UriMapping {
+  /// The file a URI maps onto, if it starts with this mapping's prefix. Any fragment
+  /// is ignored.
+  ///
+  /// # Examples
+  ///
+  /// ```rust
+  /// # use bundle_schema::retriever::UriMapping;
+  /// # use std::path::Path;
+  /// let mapping: UriMapping = "https://schemas.example.com/=schemas".parse().unwrap();
+  /// let uri = url::Url::parse("https://schemas.example.com/types/my%20money.json#/$defs/x").unwrap();
+  /// assert_eq!(mapping.path_for(&uri).unwrap(), Path::new("schemas/types/my money.json"));
+  ///
+  /// let uri = url::Url::parse("https://elsewhere.com/types/money.json").unwrap();
+  /// assert!(mapping.path_for(&uri).is_none());
+  /// ```
+  pub fn path_for(&self, uri: &Url) -> Option<PathBuf> {
+    let mut uri = uri.clone();
+    uri.set_fragment(None);
+
+    let remainder = uri.as_str().strip_prefix(&self.prefix)?;
+    let mut path = self.dir.clone();
+    for segment in remainder.split('/').filter(|s| !s.is_empty()) {
+      path.push(percent_decode_str(segment).decode_utf8_lossy().as_ref());
+    }
+    Some(path)
+  }
+}
+
+/// Reads schemas from the local filesystem: `file://` URLs directly, and other URIs
+/// through the [`UriMapping`] with the longest matching prefix.
+///
+/// Files that don't exist are left for other retrievers.
+#[derive(Debug, Clone, Default)]
+pub struct FileRetriever {
+  pub mappings: Vec<UriMapping>,
+}
+
+impl FileRetriever {
+  pub fn new(mappings: Vec<UriMapping>) -> Self {
+    FileRetriever { mappings }
+  }
+
+  /// The file `uri` would be read from, if any.
+  ///
+  /// # Examples
+  ///
+  /// ```rust
+  /// # use bundle_schema::retriever::FileRetriever;
+  /// # use std::path::Path;
+  /// let retriever = FileRetriever::new(vec![
+  ///   "https://schemas.example.com/=schemas".parse().unwrap(),
+  ///   "https://schemas.example.com/types/=vendor/types".parse().unwrap(),
+  /// ]);
+  ///
+  /// let uri = url::Url::parse("https://schemas.example.com/types/money.json").unwrap();
+  /// assert_eq!(retriever.path_for(&uri).unwrap(), Path::new("vendor/types/money.json"));
+  ///
+  /// let uri = url::Url::parse("file:///tmp/order.json").unwrap();
+  /// assert_eq!(retriever.path_for(&uri).unwrap(), Path::new("/tmp/order.json"));
+  /// ```
+  pub fn path_for(&self, uri: &Url) -> Option<PathBuf> {
+    let mapped = self
+      .mappings
+      .iter()
+      .filter(|m| uri.as_str().starts_with(&m.prefix))
+      .max_by_key(|m| m.prefix.len())
+      .and_then(|m| m.path_for(uri));
+
+    match (mapped, uri.scheme()) {
+      (Some(path), _) => Some(path),
+      (None, "file") => uri.to_file_path().ok(),
+      (None, _) => None,
+    }
+  }
+}
+
+impl SchemaRetriever for FileRetriever {
+  fn retrieve(&self, uri: &Url) -> Result<Option<InputDocument>, BundleError> {
+    let Some(path) = self.path_for(uri) else {
+      return Ok(None);
+    };
+    read_file(uri, &path)
+  }
+}
+
+fn read_file(uri: &Url, path: &Path) -> Result<Option<InputDocument>, BundleError> {
+  let source = path.display().to_string();
+  let contents = match fs::read_to_string(path) {
+    Ok(contents) => contents,
+    Err(e) if e.kind() == ErrorKind::NotFound => {
+      debug!("«{uri}» maps onto «{source}», which doesn't exist");
+      return Ok(None);
+    }
+    Err(e) => {
+      return Err(BundleError::Io {
+        path: source,
+        source: e,
+      })
+    }
+  };
+
+  debug!("Loading «{uri}» from «{source}»");
+  parse_document(&source, &contents, None).map(Some)
+}
+
+/// Fetches `http://` and `https://` schemas over the network.
+///
+/// A `404 Not Found` or `410 Gone` response leaves the schema for other retrievers;
+/// any other failure is an error.
+#[derive(Debug, Clone)]
+pub struct HttpRetriever {
+  agent: ureq::Agent,
+}
+
+impl Default for HttpRetriever {
+  fn default() -> Self {
+    Self::with_timeout(Duration::from_secs(30))
+  }
+}
+
+impl HttpRetriever {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Give up on any request that takes longer than `timeout`.
+  pub fn with_timeout(timeout: Duration) -> Self {
+    let agent = ureq::AgentBuilder::new()
+      .timeout(timeout)
+      .user_agent(concat!("bundle-schema/", env!("CARGO_PKG_VERSION")))
+      .build();
+    HttpRetriever { agent }
+  }
+}
+
+/// Schemas bigger than this are refused rather than read into memory.
+const MAX_RESPONSE_BYTES: u64 = 16 * 1024 * 1024;
+
+impl HttpRetriever {
+  /// Fetch the raw contents of `uri`, or `None` if it isn't an `http://` or `https://`
+  /// URI, or the server says it doesn't exist.
+  pub fn fetch(&self, uri: &Url) -> Result<Option<String>, BundleError> {
+    if !matches!(uri.scheme(), "http" | "https") {
+      return Ok(None);
+    }
+
+    let fetch_error = |message: String| BundleError::Fetch {
+      uri: uri.to_string(),
+      message,
+    };
+
+    debug!("Fetching «{uri}»");
+    let response = match self.agent.request_url("GET", uri).call() {
+      Ok(response) => response,
+      Err(ureq::Error::Status(404 | 410, _)) => {
+        debug!("«{uri}» doesn't exist");
+        return Ok(None);
+      }
+      Err(ureq::Error::Status(code, response)) => {
+        return Err(fetch_error(format!(
+          "HTTP {code} {}",
+          response.status_text()
+        )))
+      }
+      Err(e) => {
+        // Transport errors lead with the URL, which the message already names.
+        let message = e.to_string();
+        let prefix = format!("{uri}: ");
+        return Err(fetch_error(
+          message.strip_prefix(&prefix).unwrap_or(&message).to_owned(),
+        ));
+      }
+    };
+
+    let mut contents = String::new();
+    response
+      .into_reader()
+      .take(MAX_RESPONSE_BYTES + 1)
+      .read_to_string(&mut contents)
+      .map_err(|e| fetch_error(e.to_string()))?;
+    if contents.len() as u64 > MAX_RESPONSE_BYTES {
+      return Err(fetch_error(format!(
+        "response is bigger than {MAX_RESPONSE_BYTES} bytes"
+      )));
+    }
+
+    Ok(Some(contents))
+  }
+}
+
+impl SchemaRetriever for HttpRetriever {
+  fn retrieve(&self, uri: &Url) -> Result<Option<InputDocument>, BundleError> {
+    match self.fetch(uri)? {
+      Some(contents) => parse_document(uri.as_str(), &contents, None).map(Some),
+      None => Ok(None),
+    }
+  }
+}
+
+/// Serves schemas held in memory, keyed by URI. Handy for tests.
+///
+/// # Examples
+///
+/// ```rust
+/// # use bundle_schema::retriever::{MemoryRetriever, SchemaRetriever};
+/// let uri = url::Url::parse("https://schemas.example.com/money.json").unwrap();
+/// let mut retriever = MemoryRetriever::new();
+/// retriever.insert(uri.clone(), serde_json::json!({"type": "number"}));
+///
+/// let doc = retriever.retrieve(&uri).unwrap().unwrap();
+/// assert_eq!(doc.schema["type"], "number");
+/// assert_eq!(doc.source, "https://schemas.example.com/money.json");
+///
+/// let elsewhere = url::Url::parse("https://schemas.example.com/other.json").unwrap();
+/// assert!(retriever.retrieve(&elsewhere).unwrap().is_none());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MemoryRetriever {
+  schemas: HashMap<Url, JsonValue>,
+}
+
+impl MemoryRetriever {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Serve `schema` for `uri`, replacing whatever was served for it before.
+  pub fn insert(&mut self, uri: Url, schema: JsonValue) {
+    self.schemas.insert(normalize_uri(&uri), schema);
+  }
+}
+
+impl SchemaRetriever for MemoryRetriever {
+  fn retrieve(&self, uri: &Url) -> Result<Option<InputDocument>, BundleError> {
+    Ok(self.schemas.get(uri).map(|schema| InputDocument {
+      source: uri.to_string(),
+      schema: schema.clone(),
+      file: None,
+    }))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::{BufRead, BufReader, Write};
+  use std::net::TcpListener;
+  use std::thread;
+
+  /// Serve one canned response per connection, returning the paths that were requested.
+  fn serve(responses: Vec<(u16, &'static str)>) -> (Url, thread::JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+
+    let handle = thread::spawn(move || {
+      let mut requested = Vec::new();
+      for (status, body) in responses {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        requested.push(line.split_whitespace().nth(1).unwrap().to_owned());
+        while line != "\r\n" {
+          line.clear();
+          reader.read_line(&mut line).unwrap();
+        }
+
+        let mut stream = reader.into_inner();
+        write!(
+          stream,
+          "HTTP/1.1 {status} Whatever\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+          body.len()
+        )
+        .unwrap();
+      }
+      requested
+    });
+
+    (base, handle)
+  }
+
+  #[test]
+  fn test_http_retriever() {
+    let (base, server) = serve(vec![
+      (200, r#"{"type": "number"}"#),
+      (404, "nope"),
+      (500, "oops"),
+    ]);
+    let retriever = HttpRetriever::with_timeout(Duration::from_secs(5));
+
+    let money = base.join("types/money.json").unwrap();
+    let doc = retriever.retrieve(&money).unwrap().unwrap();
+    assert_eq!(doc.schema["type"], "number");
+    assert_eq!(doc.source, money.as_str());
+
+    let missing = base.join("missing.json").unwrap();
+    assert!(retriever.retrieve(&missing).unwrap().is_none());
+
+    let broken = base.join("broken.json").unwrap();
+    let err = retriever.retrieve(&broken).unwrap_err();
+    assert!(matches!(err, BundleError::Fetch { .. }), "{err}");
+
+    assert_eq!(
+      server.join().unwrap(),
+      ["/types/money.json", "/missing.json", "/broken.json"]
+    );
+  }
+
+  #[test]
+  fn test_http_retriever_ignores_other_schemes() {
+    let retriever = HttpRetriever::new();
+    let uri = Url::parse("urn:example:money").unwrap();
+    assert!(retriever.retrieve(&uri).unwrap().is_none());
+  }
+}
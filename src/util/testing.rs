@@ -0,0 +1,10 @@
+use std::fs;
+use std::path::PathBuf;
+
+/// An empty directory for a test to work in, unique to `name` and this process.
+pub(crate) fn scratch_dir(name: &str) -> PathBuf {
+  let dir = std::env::temp_dir().join(format!("bundle-schema-{name}-{}", std::process::id()));
+  let _ = fs::remove_dir_all(&dir);
+  fs::create_dir_all(&dir).unwrap();
+  dir
+}